@@ -0,0 +1,246 @@
+//! Canonical layout for chs source code.
+//!
+//! The formatter works on the tokens of the file, trivia included, so that
+//! every comment is kept where it was written. Only whitespace changes:
+//!
+//! - Blocks that fit on one line are written as `{ a b }` (or `{}` when
+//!   empty). Other blocks have their `{` at the end of the line that opens
+//!   them, their body indented by four spaces and `}` on a line of its own.
+//! - The header of a `fn` and whole `const` and `alloc` definitions are
+//!   joined onto one line.
+//! - Tokens on the same line are separated by one space, except after the
+//!   prefixes `&`, `$`, `@` and `!` and in `:=`. Line breaks are kept, but
+//!   several blank lines become one.
+//!
+//! Formatting is idempotent: formatting its own output changes nothing.
+//!
+//! ```
+//! let source = "fn sq : int\n-> int {dup *}\nalloc\n    8\n    8\n    +\n:= p\n";
+//! let formatted = chs_fmt::format_source(source, "sq.chs").unwrap();
+//! assert_eq!(formatted, "fn sq : int -> int { dup * }\nalloc 8 8 + := p\n");
+//! ```
+use std::fmt;
+
+use chs_lexer::{decode_source, InvalidUtf8, Lexer, Token, TokenKind, DEFAULT_TAB_WIDTH};
+use chs_parser::{parse_program, ParseError};
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    InvalidUtf8(String, InvalidUtf8),
+    Parse(ParseError),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::InvalidUtf8(filepath, e) => {
+                write!(f, "Error:\n  {} in {}{}", e, filepath, e.loc)
+            }
+            FormatError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ParseError> for FormatError {
+    fn from(e: ParseError) -> Self {
+        FormatError::Parse(e)
+    }
+}
+
+/// Format the contents of a file. See `format_source`.
+pub fn format_file(input: &[u8], filepath: &str) -> Result<String, FormatError> {
+    let source = decode_source(input, DEFAULT_TAB_WIDTH)
+        .map_err(|e| FormatError::InvalidUtf8(filepath.to_string(), e))?;
+    Ok(format_source(source, filepath)?)
+}
+
+/// Format a whole program. Programs that do not parse are left alone and the
+/// first error is returned.
+pub fn format_source(source: &str, filepath: &str) -> Result<String, ParseError> {
+    parse_program(source, filepath)?;
+    let tokens: Vec<Token> = Lexer::new(source).collect();
+    Ok(Formatter::new(&tokens).run())
+}
+
+/// What goes between two tokens.
+enum Sep {
+    None,
+    Space,
+    /// One line break, or two for a blank line.
+    Lines(usize),
+}
+
+struct Formatter<'a, 'src> {
+    tokens: &'a [Token<'src>],
+    /// For each `{`, the index of its `}` and whether the block spans lines.
+    blocks: Vec<Option<(usize, bool)>>,
+    /// Which `{` each `}` closes.
+    opens: Vec<Option<usize>>,
+    out: String,
+    depth: usize,
+    /// Inside a `fn` header or a `const`/`alloc` definition.
+    header: bool,
+    /// The next token is the name a `const` or `alloc` defines.
+    name_next: bool,
+}
+
+impl<'a, 'src> Formatter<'a, 'src> {
+    fn new(tokens: &'a [Token<'src>]) -> Self {
+        let mut blocks = vec![None; tokens.len()];
+        let mut opens = vec![None; tokens.len()];
+        let mut stack: Vec<(usize, bool)> = vec![];
+        for (i, token) in tokens.iter().enumerate() {
+            match token.kind {
+                TokenKind::OpenCurly => stack.push((i, false)),
+                TokenKind::CloseCurly => {
+                    // The program parsed, so the braces are balanced.
+                    let (open, multiline) = stack.pop().expect("balanced braces");
+                    blocks[open] = Some((i, multiline));
+                    opens[i] = Some(open);
+                    if let Some(outer) = stack.last_mut() {
+                        outer.1 |= multiline;
+                    }
+                }
+                _ if token.value.contains('\n') || ends_line(token) => {
+                    if let Some(block) = stack.last_mut() {
+                        block.1 = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self {
+            tokens,
+            blocks,
+            opens,
+            out: String::new(),
+            depth: 0,
+            header: false,
+            name_next: false,
+        }
+    }
+
+    fn run(mut self) -> String {
+        let mut prev: Option<usize> = None;
+        let mut newlines = 0;
+        for (i, token) in self.tokens.iter().enumerate() {
+            if token.kind == TokenKind::Whitespace {
+                newlines += token.value.matches('\n').count();
+                continue;
+            }
+            if let Some(p) = prev {
+                let sep = self.separator(p, i, newlines);
+                self.write_sep(sep);
+            }
+            self.write_token(i);
+            prev = Some(i);
+            newlines = 0;
+        }
+        if prev.is_some() {
+            self.out.push('\n');
+        }
+        self.out
+    }
+
+    fn multiline(&self, open: usize) -> bool {
+        self.blocks[open].is_some_and(|(_, multiline)| multiline)
+    }
+
+    fn separator(&mut self, prev: usize, curr: usize, newlines: usize) -> Sep {
+        let p = &self.tokens[prev];
+        let c = &self.tokens[curr];
+        let closes = self.opens[curr];
+        if closes == Some(prev) {
+            return Sep::None;
+        }
+        // A comment at the end of a line stays there.
+        if ends_line(c) && newlines == 0 {
+            return Sep::Space;
+        }
+        // Before the comment case, since a `}` after a comment still closes.
+        if let Some(open) = closes {
+            if self.multiline(open) {
+                self.depth -= 1;
+                return Sep::Lines(1);
+            }
+            return Sep::Space;
+        }
+        if ends_line(p) {
+            return Sep::Lines(newlines.clamp(1, 2));
+        }
+        if p.kind == TokenKind::OpenCurly && self.multiline(prev) {
+            return Sep::Lines(1);
+        }
+        if p.kind == TokenKind::OpenCurly || c.kind == TokenKind::OpenCurly {
+            return Sep::Space;
+        }
+        if is_prefix(p) || (*p == *":" && *c == *"=") {
+            return Sep::None;
+        }
+        if self.header || newlines == 0 {
+            return Sep::Space;
+        }
+        Sep::Lines(newlines.min(2))
+    }
+
+    fn write_sep(&mut self, sep: Sep) {
+        match sep {
+            Sep::None => {}
+            Sep::Space => self.out.push(' '),
+            Sep::Lines(n) => {
+                for _ in 0..n {
+                    self.out.push('\n');
+                }
+                // Lines of a header broken by a comment are continuations.
+                for _ in 0..self.depth + self.header as usize {
+                    self.out.push_str(INDENT);
+                }
+            }
+        }
+    }
+
+    fn write_token(&mut self, i: usize) {
+        let token = &self.tokens[i];
+        if ends_line(token) {
+            self.out.push_str(token.value.trim_end());
+        } else {
+            self.out.push_str(token.value);
+        }
+        match token.kind {
+            TokenKind::KeyWord if self.depth == 0 && !self.header => {
+                self.header = matches!(token.value, "fn" | "const" | "alloc");
+            }
+            TokenKind::KeyWord if self.header && *token == *"=" => self.name_next = true,
+            TokenKind::OpenCurly => {
+                self.header = false;
+                if self.multiline(i) {
+                    self.depth += 1;
+                }
+            }
+            _ if self.name_next => {
+                self.name_next = false;
+                self.header = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Line comments and doc comments run to the end of their line.
+fn ends_line(token: &Token) -> bool {
+    match token.kind {
+        TokenKind::DocComment => true,
+        TokenKind::Comment => token.value.starts_with("--"),
+        _ => false,
+    }
+}
+
+/// Tokens that are written right against the token after them.
+fn is_prefix(token: &Token) -> bool {
+    matches!(
+        (token.kind, token.value),
+        (TokenKind::KeyWord, "&" | "$") | (TokenKind::Intrinsic, "@" | "!")
+    )
+}
@@ -1,197 +1,395 @@
 use core::fmt;
+use std::{borrow::Cow, iter::FusedIterator};
 
-pub struct Lexer {
-    data: Vec<u8>,
+mod stream;
+
+pub use stream::{Relexed, TextEdit, TokenData, TokenStream};
+
+/// Lexer over borrowed source text.
+///
+/// Tokens point into the input, so lexing does not allocate. Besides
+/// `next_token` the lexer is an `Iterator`, which stops at EOF.
+///
+/// ```
+/// use chs_lexer::Lexer;
+/// let words: Vec<&str> = Lexer::new("1 dup + -- twice")
+///     .skip_trivia(true)
+///     .map(|token| token.value)
+///     .collect();
+/// assert_eq!(words, ["1", "dup", "+"]);
+/// ```
+pub struct Lexer<'src> {
+    input: &'src str,
     pos: usize,
-    max: usize,
     loc: Loc,
+    skip_trivia: bool,
+    tab_width: usize,
 }
 
-const KEYWORDS: &[&'static str] = &[
-    "debug", "if", "else", "while", "fn", "let", "alloc", "const", ":", "=", "->", "&", "$",
+const KEYWORDS: &[&str] = &[
+    "debug", "if", "else", "while", "fn", "let", "alloc", "const", "true", "false", ":", "=", "->",
+    "&", "$", "noinline",
 ];
-const INTRISIC: &[u8] = &[b'+', b'-', b'*', b'=', b':', b'>', b'<', b'!', b'@'];
+const INTRISIC: &str = "+-*/=:><!@|^~&";
+
+/// Columns a tab advances to, unless changed with `Lexer::tab_width`.
+pub const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// Identifiers start with a letter (in any script) or `_`.
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+/// After the first character identifiers may also contain digits, `.`, `!`
+/// and `@` (`Point.x!`, `Point.x@`). Everything else, including `{`, `}`,
+/// `(`, `)`, `:`, `"` and EOF, ends the identifier.
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '!' | '@')
+}
+
+/// Source that is not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidUtf8 {
+    /// Byte offset of the first invalid byte.
+    pub offset: usize,
+    pub loc: Loc,
+}
 
-impl Lexer {
-    pub fn new(data: Vec<u8>) -> Self {
-        let max = data.len();
+impl fmt::Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid UTF-8 at byte {}", self.offset)
+    }
+}
+
+/// Check that `input` is UTF-8 before handing it to the `Lexer`.
+pub fn decode_source(input: &[u8], tab_width: usize) -> Result<&str, InvalidUtf8> {
+    std::str::from_utf8(input).map_err(|e| {
+        let offset = e.valid_up_to();
+        // The prefix was just validated.
+        let valid = std::str::from_utf8(&input[..offset]).unwrap_or_default();
+        let loc = valid
+            .chars()
+            .fold(Loc::new(1, 1), |loc, c| loc.next(c, tab_width));
+        InvalidUtf8 { offset, loc }
+    })
+}
+
+impl<'src> Lexer<'src> {
+    pub fn new(input: &'src str) -> Self {
         Self {
-            data,
+            input,
             pos: 0,
-            max,
             loc: Loc::new(1, 1),
+            skip_trivia: false,
+            tab_width: DEFAULT_TAB_WIDTH,
         }
     }
-    fn curr_char(&self) -> u8 {
-        if self.pos < self.max {
-            self.data[self.pos]
-        } else {
-            0
-        }
+    /// Set how many columns a tab stop spans in the reported `Loc`s.
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = tab_width.max(1);
+        self
+    }
+    /// Leave whitespace and comments (but not doc comments) out of the output.
+    pub fn skip_trivia(mut self, skip: bool) -> Self {
+        self.skip_trivia = skip;
+        self
+    }
+    pub fn input(&self) -> &'src str {
+        self.input
+    }
+    pub fn loc(&self) -> Loc {
+        self.loc
+    }
+    fn is_eof(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+    fn curr_char(&self) -> char {
+        self.input[self.pos..].chars().next().unwrap_or('\0')
     }
     fn advance_pos(&mut self) {
-        if self.pos < self.max {
-            self.pos += 1;
-            self.loc = self.loc.next(self.curr_char());
+        if let Some(c) = self.input[self.pos..].chars().next() {
+            self.loc = self.loc.next(c, self.tab_width);
+            self.pos += c.len_utf8();
         }
     }
-    fn peek_char(&self, offset: usize) -> u8 {
-        if self.pos + offset < self.max {
-            self.data[self.pos + offset]
-        } else {
-            0
+    fn advance_by(&mut self, n: usize) {
+        for _ in 0..n {
+            self.advance_pos();
         }
     }
-    fn make_token(&self, start: usize, kind: TokenKind, start_loc: Loc) -> Token {
-        let value = String::from_utf8_lossy(&self.data[start..self.pos]).to_string();
-        Token::new(value, kind, start_loc)
+    /// Byte `offset` bytes ahead. Only used to look past ASCII characters.
+    fn peek_byte(&self, offset: usize) -> u8 {
+        self.input
+            .as_bytes()
+            .get(self.pos + offset)
+            .copied()
+            .unwrap_or(0)
     }
-    fn make_token_advance(&mut self, start: usize, kind: TokenKind) -> Token {
+    fn make_token(&self, start: usize, kind: TokenKind, start_loc: Loc) -> Token<'src> {
+        let span = Span::new(start, self.pos);
+        Token::new(&self.input[start..self.pos], kind, start_loc, span)
+    }
+    fn make_token_advance(&mut self, start: usize, kind: TokenKind) -> Token<'src> {
         let start_loc = self.loc;
         self.advance_pos();
         self.make_token(start, kind, start_loc)
     }
-    pub fn next_token(&mut self) -> Token {
-        let start = self.pos;
+    fn make_token_advance_by(&mut self, start: usize, n: usize, kind: TokenKind) -> Token<'src> {
         let start_loc = self.loc;
+        self.advance_by(n);
+        self.make_token(start, kind, start_loc)
+    }
+    pub fn next_token(&mut self) -> Token<'src> {
+        loop {
+            let token = self.lex_token();
+            if !(self.skip_trivia && token.kind.is_trivia()) {
+                return token;
+            }
+        }
+    }
+    fn lex_token(&mut self) -> Token<'src> {
+        let start = self.pos;
         match self.curr_char() {
-            c if c.is_ascii_whitespace() => self.whitespace(start),
-            b'a'..=b'z' | b'A'..=b'Z' => self.identfier(start),
-            b'0'..=b'9' => self.number(start),
-            b'\"' => self.string(start),
-            b'{' => self.make_token_advance(start, TokenKind::OpenCurly),
-            b'}' => self.make_token_advance(start, TokenKind::CloseCurly),
-            b':' => self.make_token_advance(start, TokenKind::KeyWord),
-            b'&' => self.make_token_advance(start, TokenKind::KeyWord),
-            b'$' => self.make_token_advance(start, TokenKind::KeyWord),
-            b'-' => {
-                if self.peek_char(1) == b'-' {
-                    self.pos += 2;
-                    return self.comment(start);
+            _ if self.is_eof() => self.make_token(start, TokenKind::EOF, self.loc),
+            c if c.is_whitespace() => self.whitespace(start),
+            c if is_ident_start(c) => self.identfier(start),
+            '0'..='9' => self.number(start),
+            '\"' => self.string(start),
+            '{' => {
+                if self.opens_block_comment() {
+                    return self.block_comment(start);
                 }
-                if self.peek_char(1) == b'>' {
-                    self.pos += 2;
-                    return self.make_token(start, TokenKind::KeyWord, start_loc);
+                self.make_token_advance(start, TokenKind::OpenCurly)
+            }
+            '}' => self.make_token_advance(start, TokenKind::CloseCurly),
+            '(' => self.make_token_advance(start, TokenKind::OpenParen),
+            ')' => self.make_token_advance(start, TokenKind::CloseParen),
+            ':' => self.make_token_advance(start, TokenKind::KeyWord),
+            // `&1` binds, a lone `&` is bitwise and.
+            '&' if self.peek_byte(1).is_ascii_digit() => {
+                self.make_token_advance(start, TokenKind::KeyWord)
+            }
+            '$' => self.make_token_advance(start, TokenKind::KeyWord),
+            '-' => {
+                if self.peek_byte(1) == b'-' {
+                    if self.peek_byte(2) == b'-' && self.peek_byte(3) != b'-' {
+                        return self.comment(start, TokenKind::DocComment);
+                    }
+                    return self.comment(start, TokenKind::Comment);
+                }
+                if self.peek_byte(1) == b'>' {
+                    return self.make_token_advance_by(start, 2, TokenKind::KeyWord);
                 }
                 self.make_token_advance(start, TokenKind::Intrinsic)
             }
-            b'!' => {
-                if self.peek_char(1) == b'=' {
-                    self.pos += 2;
-                    return self.make_token(start, TokenKind::Intrinsic, start_loc);
+            '!' => {
+                if self.peek_byte(1) == b'=' {
+                    return self.make_token_advance_by(start, 2, TokenKind::Intrinsic);
                 }
                 self.make_token_advance(start, TokenKind::Intrinsic)
             }
-            b'=' => {
-                if self.peek_char(1) == b'=' {
-                    self.pos += 2;
-                    return self.make_token(start, TokenKind::Intrinsic, start_loc);
+            '<' | '>' => {
+                // `<=` `>=` `<<` `>>`
+                let c = self.curr_char() as u8;
+                if self.peek_byte(1) == b'=' || self.peek_byte(1) == c {
+                    return self.make_token_advance_by(start, 2, TokenKind::Intrinsic);
                 }
-                self.make_token_advance(start, TokenKind::KeyWord)
+                self.make_token_advance(start, TokenKind::Intrinsic)
             }
-            c if INTRISIC.contains(&c) => self.make_token_advance(start, TokenKind::Intrinsic),
-            _ => {
-                if self.pos < self.max {
-                    self.make_token_advance(start, TokenKind::Invalid)
-                } else {
-                    Token::new(String::from("\0"), TokenKind::EOF, start_loc)
+            '=' => {
+                if self.peek_byte(1) == b'=' {
+                    return self.make_token_advance_by(start, 2, TokenKind::Intrinsic);
                 }
+                self.make_token_advance(start, TokenKind::KeyWord)
             }
+            c if INTRISIC.contains(c) => self.make_token_advance(start, TokenKind::Intrinsic),
+            _ => self.make_token_advance(start, TokenKind::Invalid),
         }
     }
 
-    fn string(&mut self, start: usize) -> Token {
+    fn string(&mut self, start: usize) -> Token<'src> {
         let start_loc = self.loc;
-        let mut buf = String::new();
+        self.advance_pos();
         loop {
-            self.advance_pos();
             match self.curr_char() {
-                b'\"' => break self.advance_pos(),
-                b'\0' => return self.make_token(start, TokenKind::Invalid, start_loc),
-                b'\\' => {
-                    match self.peek_char(1) {
-                        b'n' => buf.push('\n'),
-                        b'\\' => buf.push('\\'),
-                        _ => return self.make_token_advance(start, TokenKind::Invalid),
+                _ if self.is_eof() => return self.make_token(start, TokenKind::Invalid, start_loc),
+                '\"' => break self.advance_pos(),
+                '\\' => {
+                    if !matches!(self.peek_byte(1), b'n' | b'\\') {
+                        self.advance_pos();
+                        return self.make_token(start, TokenKind::Invalid, start_loc);
                     }
-                    self.advance_pos();
+                    self.advance_by(2);
                 }
-                a => buf.push(a as char),
+                _ => self.advance_pos(),
             }
         }
-        Token::new(buf, TokenKind::String, start_loc)
+        self.make_token(start, TokenKind::String, start_loc)
     }
 
-    fn identfier(&mut self, start: usize) -> Token {
+    fn identfier(&mut self, start: usize) -> Token<'src> {
         let start_loc = self.loc;
         loop {
             self.advance_pos();
-            let curr_char = &self.curr_char();
-            if curr_char.is_ascii_whitespace() {
+            if !is_ident_char(self.curr_char()) {
                 break;
             }
         }
-        let value = String::from_utf8_lossy(&self.data[start..self.pos]).to_string();
-        match value.as_str() {
-            c if KEYWORDS.contains(&c) => Token::new(value, TokenKind::KeyWord, start_loc),
-            "dup" | "drop" | "swap" | "over" | "rot" | "mod" | "offset" => {
-                Token::new(value, TokenKind::Intrinsic, start_loc)
+        let kind = match &self.input[start..self.pos] {
+            c if KEYWORDS.contains(&c) => TokenKind::KeyWord,
+            "dup" | "drop" | "swap" | "over" | "rot" | "mod" | "offset" | "and" | "or" | "not" => {
+                TokenKind::Intrinsic
             }
-            _ => Token::new(value, TokenKind::Word, start_loc),
-        }
+            _ => TokenKind::Word,
+        };
+        self.make_token(start, kind, start_loc)
     }
-    fn number(&mut self, start: usize) -> Token {
+    fn number(&mut self, start: usize) -> Token<'src> {
         let start_loc = self.loc;
         loop {
             self.advance_pos();
-            if !matches!(self.curr_char(), b'0'..=b'9') {
+            if !self.curr_char().is_ascii_digit() {
                 break;
             }
         }
         self.make_token(start, TokenKind::Interger, start_loc)
     }
-    fn whitespace(&mut self, start: usize) -> Token {
+    fn whitespace(&mut self, start: usize) -> Token<'src> {
         let start_loc = self.loc;
         loop {
             self.advance_pos();
-            if !self.curr_char().is_ascii_whitespace() {
+            if !self.curr_char().is_whitespace() {
                 break;
             }
         }
 
         self.make_token(start, TokenKind::Whitespace, start_loc)
     }
-    fn comment(&mut self, start: usize) -> Token {
+    fn comment(&mut self, start: usize, kind: TokenKind) -> Token<'src> {
         let start_loc = self.loc;
-        loop {
+        while !self.is_eof() && self.curr_char() != '\n' {
             self.advance_pos();
-            if matches!(self.curr_char(), b'\n' | b'\0') {
-                break;
+        }
+        self.make_token(start, kind, start_loc)
+    }
+    /// `{-` and whitespace, since `{-}` and `{-1}` are a body with `-`.
+    fn opens_block_comment(&self) -> bool {
+        self.curr_char() == '{'
+            && self.peek_byte(1) == b'-'
+            && self.peek_byte(2).is_ascii_whitespace()
+    }
+    /// `{- ... -}` comments, which may be nested.
+    fn block_comment(&mut self, start: usize) -> Token<'src> {
+        let start_loc = self.loc;
+        let mut depth = 0;
+        loop {
+            match (self.curr_char(), self.peek_byte(1)) {
+                _ if self.is_eof() => return self.make_token(start, TokenKind::Invalid, start_loc),
+                ('{', b'-') => {
+                    depth += 1;
+                    self.advance_pos();
+                }
+                ('-', b'}') => {
+                    depth -= 1;
+                    self.advance_pos();
+                    if depth == 0 {
+                        self.advance_pos();
+                        break;
+                    }
+                }
+                _ => {}
             }
+            self.advance_pos();
         }
         self.make_token(start, TokenKind::Comment, start_loc)
     }
 }
 
-#[derive(Debug)]
-pub struct Token {
+impl<'src> Iterator for Lexer<'src> {
+    type Item = Token<'src>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.next_token();
+        (token.kind != TokenKind::EOF).then_some(token)
+    }
+}
+
+impl FusedIterator for Lexer<'_> {}
+
+/// Byte range of a token in the source.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+    pub fn shift(&self, delta: isize) -> Self {
+        Self {
+            start: (self.start as isize + delta) as usize,
+            end: (self.end as isize + delta) as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Token<'src> {
     pub loc: Loc,
-    pub value: String,
+    pub span: Span,
+    /// Source text of the token. `String` tokens keep their quotes and
+    /// escapes, see `Token::unescape`.
+    pub value: &'src str,
     pub kind: TokenKind,
 }
 
-impl PartialEq<str> for Token {
+impl PartialEq<str> for Token<'_> {
     fn eq(&self, other: &str) -> bool {
         self.value == other
     }
-    fn ne(&self, other: &str) -> bool {
-        self.value != other
-    }
 }
 
-impl Token {
-    pub fn new(value: String, kind: TokenKind, loc: Loc) -> Self {
-        Self { loc, value, kind }
+impl<'src> Token<'src> {
+    pub fn new(value: &'src str, kind: TokenKind, loc: Loc, span: Span) -> Self {
+        Self {
+            loc,
+            span,
+            value,
+            kind,
+        }
+    }
+    /// Contents of a `String` token with the quotes removed and escapes
+    /// resolved. Only allocates when there are escapes.
+    pub fn unescape(&self) -> Cow<'src, str> {
+        let inner = self
+            .value
+            .strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .unwrap_or(self.value);
+        if !inner.contains('\\') {
+            return Cow::Borrowed(inner);
+        }
+        let mut buf = String::with_capacity(inner.len());
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => buf.push('\n'),
+                    Some(c) => buf.push(c),
+                    None => buf.push('\\'),
+                },
+                c => buf.push(c),
+            }
+        }
+        Cow::Owned(buf)
     }
 }
 
@@ -201,6 +399,7 @@ pub enum TokenKind {
     Invalid,
     Whitespace,
     Comment,
+    DocComment,
     Interger,
     String,
     KeyWord,
@@ -208,6 +407,15 @@ pub enum TokenKind {
     Intrinsic,
     OpenCurly,
     CloseCurly,
+    OpenParen,
+    CloseParen,
+}
+
+impl TokenKind {
+    /// Tokens with no meaning to the parser.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, TokenKind::Whitespace | TokenKind::Comment)
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Default)]
@@ -239,17 +447,28 @@ impl Loc {
             col: 1,
         }
     }
-    pub fn next(&self, c: u8) -> Self {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+    pub fn col(&self) -> usize {
+        self.col
+    }
+    pub fn shift_lines(&self, delta: isize) -> Self {
+        Self {
+            line: (self.line as isize + delta) as usize,
+            col: self.col,
+        }
+    }
+    /// Location after `c`. Columns count characters, and a tab moves to the
+    /// next multiple of `tab_width` (plus one, as columns start at 1).
+    pub fn next(&self, c: char, tab_width: usize) -> Self {
         match c {
-            b'\n' => self.next_line(),
-            b'\t' => {
-                let ts = 8;
-                Self {
-                    line: self.line,
-                    col: (self.col / ts) * ts + ts,
-                }
-            }
-            c if (c as char).is_control() => *self,
+            '\n' => self.next_line(),
+            '\t' => Self {
+                line: self.line,
+                col: ((self.col - 1) / tab_width + 1) * tab_width + 1,
+            },
+            c if c.is_control() => *self,
             _ => self.next_column(),
         }
     }
@@ -0,0 +1,192 @@
+use std::ops::Range;
+
+use crate::{Lexer, Loc, Span, Token, TokenKind, DEFAULT_TAB_WIDTH};
+
+/// Replace the bytes in `range` of the current text with `text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+impl TextEdit {
+    pub fn new(range: Range<usize>, text: impl Into<String>) -> Self {
+        Self {
+            range,
+            text: text.into(),
+        }
+    }
+}
+
+/// A token stored in a `TokenStream`; its text lives in the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenData {
+    pub kind: TokenKind,
+    pub span: Span,
+    pub loc: Loc,
+}
+
+/// What `TokenStream::edit` had to lex again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relexed {
+    /// Indices of the new tokens in the stream.
+    pub tokens: Range<usize>,
+    /// Bytes covered by the replaced tokens in the old text.
+    pub old: Range<usize>,
+    /// Bytes covered by the new tokens in the new text.
+    pub new: Range<usize>,
+    /// How far the tokens after the edit moved, in bytes and in lines.
+    pub byte_delta: isize,
+    pub line_delta: isize,
+}
+
+/// Source text together with all of its tokens, trivia included. The last
+/// token is always EOF.
+///
+/// Edits only lex the text around them again: lexing restarts at the first
+/// token the edit touches and stops as soon as it reaches the start of an old
+/// token after the edit at the same column, since from there on the text,
+/// and so the tokens, are the same as before.
+///
+/// ```
+/// use chs_lexer::{TextEdit, TokenStream};
+/// let mut stream = TokenStream::new("1 2 +\ndebug".to_string());
+/// let relexed = stream.edit(&TextEdit::new(2..3, "20"));
+/// assert_eq!(stream.source(), "1 20 +\ndebug");
+/// assert_eq!(stream.get(2).unwrap().value, "20");
+/// // `debug` was not lexed again, only moved.
+/// assert!(relexed.tokens.end < stream.len() - 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TokenStream {
+    source: String,
+    tokens: Vec<TokenData>,
+    tab_width: usize,
+}
+
+impl TokenStream {
+    pub fn new(source: String) -> Self {
+        Self::with_tab_width(source, DEFAULT_TAB_WIDTH)
+    }
+    pub fn with_tab_width(source: String, tab_width: usize) -> Self {
+        let mut tokens = vec![];
+        let mut lexer = Self::lexer(&source, tab_width, 0, Loc::new(1, 1));
+        loop {
+            let token = lexer.next_token();
+            tokens.push(TokenData::from(&token));
+            if token.kind == TokenKind::EOF {
+                break;
+            }
+        }
+        Self {
+            source,
+            tokens,
+            tab_width,
+        }
+    }
+    fn lexer(source: &str, tab_width: usize, pos: usize, loc: Loc) -> Lexer<'_> {
+        let mut lexer = Lexer::new(source).tab_width(tab_width);
+        lexer.pos = pos;
+        lexer.loc = loc;
+        lexer
+    }
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+    pub fn tokens(&self) -> &[TokenData] {
+        &self.tokens
+    }
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<Token<'_>> {
+        self.tokens.get(index).map(|data| self.token(data))
+    }
+    pub fn iter(&self) -> impl Iterator<Item = Token<'_>> {
+        self.tokens.iter().map(|data| self.token(data))
+    }
+    fn token(&self, data: &TokenData) -> Token<'_> {
+        let text = match data.kind {
+            TokenKind::EOF => "\0",
+            _ => &self.source[data.span.start..data.span.end],
+        };
+        Token::new(text, data.kind, data.loc, data.span)
+    }
+    /// Index of the first token that ends at or after `offset`.
+    pub fn token_at(&self, offset: usize) -> usize {
+        self.tokens.partition_point(|t| t.span.end < offset)
+    }
+
+    /// Apply `edit` and lex the affected part of the text again.
+    ///
+    /// Panics if the range is out of bounds or not on `char` boundaries,
+    /// like `String::replace_range`.
+    pub fn edit(&mut self, edit: &TextEdit) -> Relexed {
+        let TextEdit { range, text } = edit;
+        // A token ending right where the edit starts may grow into it, and
+        // a `{` looks two bytes ahead for a block comment.
+        let first = self
+            .token_at(range.start.saturating_sub(1))
+            .min(self.tokens.len() - 1);
+        let start = self.tokens[first].span.start;
+        let start_loc = self.tokens[first].loc;
+        let byte_delta = text.len() as isize - range.len() as isize;
+        let edit_end = range.start + text.len();
+        let old_len = self.source.len();
+        self.source.replace_range(range.clone(), text);
+
+        let mut old = first;
+        let mut new_tokens: Vec<TokenData> = vec![];
+        let mut lexer = Self::lexer(&self.source, self.tab_width, start, start_loc);
+        let mut line_delta = 0;
+        loop {
+            let token = lexer.next_token();
+            if token.span.start >= edit_end {
+                let old_start = (token.span.start as isize - byte_delta) as usize;
+                while old < self.tokens.len() && self.tokens[old].span.start < old_start {
+                    old += 1;
+                }
+                if let Some(data) = self.tokens.get(old) {
+                    if data.span.start == old_start && data.loc.col() == token.loc.col() {
+                        line_delta = token.loc.line() as isize - data.loc.line() as isize;
+                        break;
+                    }
+                }
+            }
+            new_tokens.push(TokenData::from(&token));
+            if token.kind == TokenKind::EOF {
+                old = self.tokens.len();
+                break;
+            }
+        }
+
+        let old_range = start..self.tokens.get(old).map_or(old_len, |t| t.span.start);
+        let new_range = start..new_tokens.last().map_or(start, |t| t.span.end);
+        for data in self.tokens[old..].iter_mut() {
+            data.span = data.span.shift(byte_delta);
+            data.loc = data.loc.shift_lines(line_delta);
+        }
+        let count = new_tokens.len();
+        self.tokens.splice(first..old, new_tokens);
+        Relexed {
+            tokens: first..first + count,
+            old: old_range,
+            new: new_range,
+            byte_delta,
+            line_delta,
+        }
+    }
+}
+
+impl From<&Token<'_>> for TokenData {
+    fn from(token: &Token) -> Self {
+        Self {
+            kind: token.kind,
+            span: token.span,
+            loc: token.loc,
+        }
+    }
+}
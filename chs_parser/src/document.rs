@@ -0,0 +1,286 @@
+use std::{collections::HashMap, ops::Range};
+
+use chs_lexer::{Span, TextEdit, Token, TokenKind, TokenStream};
+
+use crate::{
+    parse_item, visit::walk_op_mut, MutVisitor, Operation, OperationKind, ParseError, Parser,
+    TokenSource,
+};
+
+/// One top-level definition or expression of a `Document`.
+#[derive(Debug, Clone)]
+pub struct Item {
+    /// Bytes of the item, from its doc comment or first token to its last token.
+    pub span: Span,
+    pub result: Result<Operation, ParseError>,
+    /// End of the last token parsing the item read. This can be past `span`:
+    /// the parser may have looked at the next token, and after an error the
+    /// tokens up to the next item are skipped.
+    read_end: usize,
+}
+
+/// A parsed source file that can be edited in place, for editors and
+/// language servers.
+///
+/// Unlike `parse_program` the whole file is always parsed: an item with an
+/// error is kept as an `Err` and parsing goes on at the next `fn`, `const` or
+/// `alloc`, together with the doc comments right before it. After an edit
+/// only the items around it are parsed again.
+///
+/// ```
+/// use chs_lexer::TextEdit;
+/// use chs_parser::Document;
+/// let source = "fn a : -> { 1 debug }\nfn b : -> { 2 debug }\n";
+/// let mut doc = Document::new(source.to_string(), "a.chs".to_string());
+/// assert_eq!(doc.items().len(), 2);
+/// let reparsed = doc.edit(&TextEdit::new(12..13, "10 +"));
+/// assert_eq!(reparsed, 0..1);
+/// assert_eq!(doc.errors().count(), 0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Document {
+    filepath: String,
+    tokens: TokenStream,
+    items: Vec<Item>,
+}
+
+/// Hands the tokens of a `TokenStream` to the parser, leaving trivia out.
+struct StreamCursor<'src> {
+    stream: &'src TokenStream,
+    index: usize,
+}
+
+impl<'src> TokenSource<'src> for StreamCursor<'src> {
+    fn next_token(&mut self) -> Token<'src> {
+        loop {
+            let token = self.stream.get(self.index).expect("stream ends with EOF");
+            if token.kind != TokenKind::EOF {
+                self.index += 1;
+            }
+            if !token.kind.is_trivia() {
+                return token;
+            }
+        }
+    }
+}
+
+impl Document {
+    pub fn new(source: String, filepath: String) -> Self {
+        let mut doc = Self {
+            filepath,
+            tokens: TokenStream::new(source),
+            items: vec![],
+        };
+        let mut consts = HashMap::default();
+        let mut index = 0;
+        while let Some((item, next)) = doc.parse_item_at(index, &mut consts) {
+            doc.items.push(item);
+            index = next;
+        }
+        doc
+    }
+
+    pub fn filepath(&self) -> &str {
+        &self.filepath
+    }
+    pub fn source(&self) -> &str {
+        self.tokens.source()
+    }
+    pub fn tokens(&self) -> &TokenStream {
+        &self.tokens
+    }
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+    /// Operations of the items that parsed.
+    pub fn operations(&self) -> impl Iterator<Item = &Operation> {
+        self.items
+            .iter()
+            .filter_map(|item| item.result.as_ref().ok())
+    }
+    pub fn errors(&self) -> impl Iterator<Item = &ParseError> {
+        self.items
+            .iter()
+            .filter_map(|item| item.result.as_ref().err())
+    }
+
+    /// Apply `edit` and parse the items it touches again. Returns the indices
+    /// of the new items; the ones after them are only moved.
+    ///
+    /// Parsing starts one item before the first one that read a token the
+    /// edit touches, since text added between two items may belong to the
+    /// first. It stops at the first old item past the edit that starts at
+    /// the same place, as long as the consts defined up to there are still
+    /// the same.
+    pub fn edit(&mut self, edit: &TextEdit) -> Range<usize> {
+        let relexed = self.tokens.edit(edit);
+        let first = self
+            .items
+            .partition_point(|item| item.read_end < relexed.old.start)
+            .saturating_sub(1);
+        let mut index = match self.items.get(first) {
+            Some(item) if first > 0 => self.token_index(item.span.start),
+            _ => 0,
+        };
+
+        let mut consts = HashMap::default();
+        for item in &self.items[..first] {
+            define_const(&mut consts, item);
+        }
+        let mut old_consts = consts.clone();
+        let mut old = first;
+        let mut new_items: Vec<Item> = vec![];
+        loop {
+            let start = self.skip_trivia(index);
+            let start = self.tokens.tokens()[start].span.start;
+            if start >= relexed.new.end {
+                let old_start = (start as isize - relexed.byte_delta) as usize;
+                while old < self.items.len() && self.items[old].span.start < old_start {
+                    define_const(&mut old_consts, &self.items[old]);
+                    old += 1;
+                }
+                if old < self.items.len()
+                    && self.items[old].span.start == old_start
+                    && consts == old_consts
+                {
+                    break;
+                }
+            }
+            match self.parse_item_at(index, &mut consts) {
+                Some((item, next)) => {
+                    new_items.push(item);
+                    index = next;
+                }
+                None => {
+                    old = self.items.len();
+                    break;
+                }
+            }
+        }
+
+        for item in self.items[old..].iter_mut() {
+            item.span = item.span.shift(relexed.byte_delta);
+            item.read_end = (item.read_end as isize + relexed.byte_delta) as usize;
+            match &mut item.result {
+                Ok(op) => ShiftLines(relexed.line_delta).visit_op_mut(op),
+                Err(e) => e.loc = e.loc.shift_lines(relexed.line_delta),
+            }
+        }
+        let count = new_items.len();
+        self.items.splice(first..old, new_items);
+        first..first + count
+    }
+
+    /// Index of the first token starting at or after `offset`.
+    fn token_index(&self, offset: usize) -> usize {
+        self.tokens
+            .tokens()
+            .partition_point(|t| t.span.start < offset)
+    }
+
+    fn skip_trivia(&self, mut index: usize) -> usize {
+        let tokens = self.tokens.tokens();
+        while tokens[index].kind.is_trivia() {
+            index += 1;
+        }
+        index
+    }
+
+    /// Parse the item starting at token `index`. Returns it together with the
+    /// index where the next item starts, or `None` at EOF.
+    fn parse_item_at(
+        &self,
+        index: usize,
+        consts: &mut HashMap<String, usize>,
+    ) -> Option<(Item, usize)> {
+        let index = self.skip_trivia(index);
+        let tokens = self.tokens.tokens();
+        if tokens[index].kind == TokenKind::EOF {
+            return None;
+        }
+        let start = tokens[index].span.start;
+        let cursor = StreamCursor {
+            stream: &self.tokens,
+            index,
+        };
+        let mut p = Parser::new(Box::new(cursor), &self.filepath, std::mem::take(consts));
+        let result = parse_item(&mut p);
+        *consts = p.consts;
+        match result {
+            Ok(Some(op)) => {
+                let item = Item {
+                    span: Span::new(start, p.end),
+                    result: Ok(op),
+                    read_end: p.read.end,
+                };
+                Some((item, self.token_index(p.end)))
+            }
+            // Only a doc comment was left.
+            Ok(None) => None,
+            Err(e) => {
+                // Go on from the token that failed, which can be the start of
+                // the next definition, with the doc comments read before it.
+                let mut failed = self.token_index(p.read.start);
+                while failed > index + 1 {
+                    let kind = tokens[failed - 1].kind;
+                    if kind != TokenKind::DocComment && !kind.is_trivia() {
+                        break;
+                    }
+                    failed -= 1;
+                }
+                let next = self.recover(failed.max(index + 1));
+                let end = tokens[..next]
+                    .iter()
+                    .rev()
+                    .find(|t| !t.kind.is_trivia())
+                    .map_or(start, |t| t.span.end);
+                let item = Item {
+                    span: Span::new(start, end),
+                    result: Err(e),
+                    read_end: p.read.end.max(tokens[next].span.start),
+                };
+                Some((item, next))
+            }
+        }
+    }
+
+    /// Index of the first token from `index` on that can start a definition:
+    /// a `fn`, `const` or `alloc`, or the first of the doc comments right
+    /// before it.
+    fn recover(&self, mut index: usize) -> usize {
+        let mut docs = None;
+        loop {
+            let token = self.tokens.get(index).expect("stream ends with EOF");
+            match token.kind {
+                TokenKind::EOF => return index,
+                TokenKind::KeyWord if matches!(token.value, "fn" | "const" | "alloc") => {
+                    return docs.unwrap_or(index)
+                }
+                TokenKind::DocComment => {
+                    docs.get_or_insert(index);
+                }
+                kind if kind.is_trivia() => {}
+                _ => docs = None,
+            }
+            index += 1;
+        }
+    }
+}
+
+fn define_const(consts: &mut HashMap<String, usize>, item: &Item) {
+    if let Ok(op) = &item.result {
+        if let OperationKind::Const(name, value, _) = &op.kind {
+            consts.insert(name.clone(), *value);
+        }
+    }
+}
+
+/// Moves the locations of operations after an edit that added or removed lines.
+struct ShiftLines(isize);
+
+impl MutVisitor for ShiftLines {
+    fn visit_op_mut(&mut self, op: &mut Operation) {
+        op.loc = op.loc.shift_lines(self.0);
+        walk_op_mut(self, op);
+    }
+}
@@ -1,537 +1,382 @@
-use std::{collections::HashMap, process::exit, rc::Rc};
+use std::{collections::HashMap, fmt, process::exit, rc::Rc, str::FromStr};
 
-use chs_lexer::{Lexer, Loc, Token, TokenKind};
+use chs_lexer::{decode_source, Lexer, Span, Token, TokenKind, DEFAULT_TAB_WIDTH};
 
-struct Parser {
-    pub lexer: Lexer,
+mod document;
+mod print;
+mod syscall;
+pub mod visit;
+
+pub use chs_lexer::Loc;
+pub use document::{Document, Item};
+pub use print::{to_sexp, to_source, Sexp};
+pub use syscall::{Syscall, OPEN_APPEND, OPEN_CREATE, OPEN_READ, OPEN_TRUNCATE, OPEN_WRITE};
+pub use visit::{Fold, MutVisitor, Visitor};
+
+/// Where the parser reads its tokens from. Trivia is already left out and
+/// EOF keeps being returned at the end of the input.
+trait TokenSource<'src> {
+    fn next_token(&mut self) -> Token<'src>;
+}
+
+impl<'src> TokenSource<'src> for Lexer<'src> {
+    fn next_token(&mut self) -> Token<'src> {
+        Lexer::next_token(self)
+    }
+}
+
+struct Parser<'src> {
+    tokens: Box<dyn TokenSource<'src> + 'src>,
     pub filepath: String,
-    pub peeked: Option<Token>,
+    pub peeked: Option<Token<'src>>,
+    /// Doc comment (`---`) found right before the last token returned by `next`.
+    pub doc: Option<String>,
+    /// Byte offset right after the last token returned by `next`.
+    pub end: usize,
+    /// The last token read from the source, which may only have been peeked.
+    pub read: Span,
     consts: HashMap<String, usize>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub filepath: String,
+    pub loc: Loc,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Error:\n  {} in {}{}",
+            self.message, self.filepath, self.loc
+        )
+    }
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
 pub fn parse_file(input: Vec<u8>, filepath: String) -> Vec<Operation> {
-    let lexer = Lexer::new(input);
-    let mut ops: Vec<Operation> = vec![];
-    let mut p = Parser {
-        lexer,
-        filepath,
-        peeked: None,
-        consts: HashMap::default(),
+    let input = match decode_source(&input, DEFAULT_TAB_WIDTH) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Error:\n  {} in {}{}", e, filepath, e.loc);
+            exit(-1);
+        }
     };
-
-    loop {
-        let token = p.next();
-        if token.kind == TokenKind::EOF {
-            break;
+    match parse_program(input, &filepath) {
+        Ok(ops) => ops,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(-1);
         }
-        ops.push(match token.kind {
-            TokenKind::KeyWord if token == *"fn" => parse_fn_expr(&mut p),
-            TokenKind::KeyWord if token == *"alloc" => parse_alloc_expr(&mut p),
-            TokenKind::KeyWord if token == *"const" => {
-                parse_const_expr(&mut p);
-                continue;
-            }
+    }
+}
+
+/// Parse a whole program, stopping at the first error.
+pub fn parse_program(input: &str, filepath: &str) -> Result<Vec<Operation>, ParseError> {
+    let lexer = Lexer::new(input).skip_trivia(true);
+    let mut p = Parser::new(Box::new(lexer), filepath, HashMap::default());
+    let mut ops: Vec<Operation> = vec![];
+    while let Some(op) = parse_item(&mut p)? {
+        ops.push(op);
+    }
+    Ok(ops)
+}
 
-            // TokenKind::OpenCurly => continue,
-            _ => parse_expr(&mut p, token),
-        })
+/// Parse one top-level definition or expression. `None` at EOF.
+fn parse_item(p: &mut Parser) -> ParseResult<Option<Operation>> {
+    let token = p.next();
+    if token.kind == TokenKind::EOF {
+        return Ok(None);
     }
-    return ops;
+    let doc = p.doc.take();
+    let loc = token.loc;
+    let kind = match token.kind {
+        TokenKind::KeyWord if token == *"fn" => parse_fn_expr(p, doc)?,
+        TokenKind::KeyWord if token == *"alloc" => parse_alloc_expr(p, doc)?,
+        TokenKind::KeyWord if token == *"const" => parse_const_expr(p, doc)?,
+
+        // TokenKind::OpenCurly => continue,
+        _ => return parse_expr(p, token).map(Some),
+    };
+    Ok(Some(Operation::new(kind, loc)))
 }
 
-fn parse_fn_expr(p: &mut Parser) -> Operation {
+fn parse_fn_expr(p: &mut Parser, doc: Option<String>) -> ParseResult<OperationKind> {
     let name = match p.expect(TokenKind::Word) {
-        Ok(token) => token.value,
-        Err(e) => {
-            eprintln!("Error:\n  Expect function name in {}{}", p.filepath, e);
-            exit(-1);
-        }
+        Ok(token) => token.value.to_string(),
+        Err(e) => return Err(p.error(e, "Expect function name")),
     };
     let mut args: Vec<String> = vec![];
     loop {
         match p.require() {
             Ok(token) if token == *":" => break,
-            Ok(token) if token.kind == TokenKind::Word => args.push(token.value),
+            Ok(token) if token.kind == TokenKind::Word => args.push(token.value.to_string()),
             Ok(token) => {
-                eprintln!(
-                    "Error:\n  Expect argument name but got `{}` in {}{}",
-                    token.value, p.filepath, token.loc
-                );
-                exit(-1);
-            }
-            Err(e) => {
-                eprintln!("Error:\n  Expect `:` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
+                return Err(p.error(
+                    token.loc,
+                    format!("Expect argument name but got `{}`", token.value),
+                ))
             }
+            Err(e) => return Err(p.error(e, "Expect `:` but got EOF")),
         }
     }
     let mut ins: Vec<DataType> = vec![];
     loop {
         match p.require() {
             Ok(token) if token == *"->" => break,
-            Ok(token) if token.kind == TokenKind::Word => {
-                let typ = match token.value.as_str() {
-                    "ptr" => DataType::Ptr,
-                    "int" => DataType::Int,
-                    "bool" => DataType::Bool,
-                    _ => {
-                        eprintln!(
-                            "Error:\n  Expect Type but got `{}` in {}{}",
-                            token.value, p.filepath, token.loc
-                        );
-                        exit(-1);
-                    }
-                };
-
-                ins.push(typ);
-            }
-            Ok(token) => {
-                eprintln!(
-                    "Error:\n  Expect Type but got `{}` in {}{}",
-                    token.value, p.filepath, token.loc
-                );
-                exit(-1);
-            }
-            Err(e) => {
-                eprintln!("Error:\n  Expect `->` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
-            }
+            Ok(token) => ins.push(parse_type(p, &token)?),
+            Err(e) => return Err(p.error(e, "Expect `->` but got EOF")),
         }
     }
     let mut outs: Vec<DataType> = vec![];
+    let mut noinline = false;
     loop {
         match p.require() {
             Ok(token) if token == *"{" => break,
-            Ok(token) if token.kind == TokenKind::Word => {
-                let typ = match token.value.as_str() {
-                    "ptr" => DataType::Ptr,
-                    "int" => DataType::Int,
-                    "bool" => DataType::Bool,
-                    _ => {
-                        eprintln!(
-                            "Error:\n  Expect Type but got `{}` in {}{}",
-                            token.value, p.filepath, token.loc
-                        );
-                        exit(-1);
-                    }
-                };
-
-                outs.push(typ);
-            }
-            Ok(token) => {
-                eprintln!(
-                    "Error:\n  Expect Type but got `{}` in {}{}",
-                    token.value, p.filepath, token.loc
-                );
-                exit(-1);
-            }
-            Err(e) => {
-                eprintln!("Error:\n  Expect `{{` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
-            }
+            Ok(token) if token == *"noinline" => match p.require() {
+                Ok(token) if token == *"{" => {
+                    noinline = true;
+                    break;
+                }
+                Ok(token) => {
+                    return Err(p.error(
+                        token.loc,
+                        format!("Expect `{{` after `noinline` but got `{}`", token.value),
+                    ))
+                }
+                Err(e) => return Err(p.error(e, "Expect `{` but got EOF")),
+            },
+            Ok(token) => outs.push(parse_type(p, &token)?),
+            Err(e) => return Err(p.error(e, "Expect `{` but got EOF")),
         }
     }
+    let body = parse_body(p)?;
+    Ok(OperationKind::Fn(
+        name,
+        args.into(),
+        ins.into(),
+        outs.into(),
+        body.into(),
+        doc,
+        noinline,
+    ))
+}
+
+fn parse_type(p: &mut Parser, token: &Token) -> ParseResult<DataType> {
+    let typ = match DataType::from_name(token.value) {
+        Some(typ) if token.kind == TokenKind::Word => typ,
+        _ => return Err(p.error(token.loc, format!("Expect Type but got `{}`", token.value))),
+    };
+    Ok(typ)
+}
+
+/// Operations of a `{ ... }` block, after the `{`.
+fn parse_body(p: &mut Parser) -> ParseResult<Vec<Operation>> {
     let mut body: Vec<Operation> = vec![];
     loop {
         match p.require() {
             Ok(token) if token.kind == TokenKind::CloseCurly => break,
-            Ok(token) => body.push(parse_expr(p, token)),
-            Err(e) => {
-                eprintln!("Error:\n  Expect `}}` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
-            }
+            Ok(token) => body.push(parse_expr(p, token)?),
+            Err(e) => return Err(p.error(e, "Expect `}` but got EOF")),
         }
     }
-    Operation::Fn(name, args.into(), ins.into(), outs.into(), body.into())
+    Ok(body)
 }
 
-fn parse_const_expr(p: &mut Parser) {
-    let mut value = vec![];
+/// Value of `const` and `alloc` definitions: integers, consts, `+` and `*`
+/// in postfix order, up to the `:` of `:=`.
+fn parse_const_value(p: &mut Parser) -> ParseResult<usize> {
+    let mut value: Vec<usize> = vec![];
     loop {
         match p.require() {
             Ok(token) if token == *":" => break,
-            Ok(token) if token.kind == TokenKind::Word => {
-                let val = p.consts.get(&token.value);
-                if val.is_none() {
-                    eprintln!(
-                        "Error:\n  Unkwon CONST but got {:?} in {}{}",
-                        token.kind, p.filepath, token.loc
-                    );
-                    exit(-1);
+            Ok(token) if token.kind == TokenKind::Word => match p.consts.get(token.value) {
+                Some(val) => value.push(*val),
+                None => {
+                    return Err(p.error(token.loc, format!("Unkwon CONST but got {:?}", token.kind)))
                 }
-                value.push(*val.unwrap());
-            }
-            Ok(token) if token.kind == TokenKind::Interger => {
-                let val = token
-                    .value
-                    .parse()
-                    .map_err(|e| eprintln!("ParseIntError: {} in {:?}", e, token))
-                    .expect("ParseInt");
-                value.push(val);
-            }
-            Ok(token) if token == *"+" => {
+            },
+            Ok(token) if token.kind == TokenKind::Interger => value.push(parse_int(p, &token)?),
+            Ok(token) if token == *"+" || token == *"*" => {
                 if value.len() < 2 {
-                    eprintln!(
-                        "Error:\n  TODO but got {:?} in {}{}",
-                        token.kind, p.filepath, token.loc
-                    );
-                    exit(-1);
+                    return Err(p.error(token.loc, format!("TODO but got {:?}", token.kind)));
                 }
-                let sum = value.pop().unwrap() + value.pop().unwrap();
-                value.push(sum);
-            }
-            Ok(token) if token == *"*" => {
-                if value.len() < 2 {
-                    eprintln!(
-                        "Error:\n  TODO but got {:?} in {}{}",
-                        token.kind, p.filepath, token.loc
-                    );
-                    exit(-1);
+                let b = value.pop().unwrap();
+                let a = value.pop().unwrap();
+                let result = if token == *"+" {
+                    a.checked_add(b)
+                } else {
+                    a.checked_mul(b)
+                };
+                match result {
+                    Some(result) => value.push(result),
+                    None => {
+                        return Err(p.error(
+                            token.loc,
+                            format!("`{}` of {} and {} is too large", token.value, a, b),
+                        ))
+                    }
                 }
-                let sum = value.pop().unwrap() * value.pop().unwrap();
-                value.push(sum);
             }
             Ok(token) => {
-                eprintln!(
-                    "Error:\n  Expect Interger, `+` of `*` but got {:?} in {}{}",
-                    token.kind, p.filepath, token.loc
-                );
-                exit(-1);
-            }
-            Err(e) => {
-                eprintln!("Error:\n  Expect `:` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
+                return Err(p.error(
+                    token.loc,
+                    format!("Expect Interger, `+` of `*` but got {:?}", token.kind),
+                ))
             }
+            Err(e) => return Err(p.error(e, "Expect `:` but got EOF")),
         }
     }
 
     match p.require() {
         Ok(token) if token == *"=" => {}
-        Ok(token) => {
-            eprintln!(
-                "Error:\n  Expect `=` but got {:?} in {}{}",
-                token.kind, p.filepath, token.loc
-            );
-            exit(-1);
-        }
-        Err(e) => {
-            eprintln!("Error:\n  Expect `=` but got EOF in {}{}", p.filepath, e);
-            exit(-1);
-        }
+        Ok(token) => return Err(p.error(token.loc, format!("Expect `=` but got {:?}", token.kind))),
+        Err(e) => return Err(p.error(e, "Expect `=` but got EOF")),
     }
-
-    match p.expect(TokenKind::Word) {
-        Ok(token) => {
-            p.consts.insert(token.value, value[0]);
-        }
-        Err(e) => {
-            eprintln!("Error:\n  Expect a Word in {}{}", p.filepath, e);
-            exit(-1);
+    match value.first() {
+        Some(val) => Ok(*val),
+        None => {
+            let loc = p.loc();
+            Err(p.error(loc, "Expect a value before `:=`"))
         }
     }
 }
 
-fn parse_alloc_expr(p: &mut Parser) -> Operation {
-    let mut value = vec![];
-    loop {
-        match p.require() {
-            Ok(token) if token == *":" => break,
-            Ok(token) if token.kind == TokenKind::Word => {
-                let val = p.consts.get(&token.value);
-                if val.is_none() {
-                    eprintln!(
-                        "Error:\n  Unkwon CONST but got {:?} in {}{}",
-                        token.kind, p.filepath, token.loc
-                    );
-                    exit(-1);
-                }
-                value.push(*val.unwrap());
-            }
-            Ok(token) if token.kind == TokenKind::Interger => {
-                let val = token
-                    .value
-                    .parse()
-                    .map_err(|e| eprintln!("ParseIntError: {} in {:?}", e, token))
-                    .expect("ParseInt");
-                value.push(val);
-            }
-            Ok(token) if token == *"+" => {
-                if value.len() < 2 {
-                    eprintln!(
-                        "Error:\n  TODO but got {:?} in {}{}",
-                        token.kind, p.filepath, token.loc
-                    );
-                    exit(-1);
-                }
-                let sum = value.pop().unwrap() + value.pop().unwrap();
-                value.push(sum);
-            }
-            Ok(token) if token == *"*" => {
-                if value.len() < 2 {
-                    eprintln!(
-                        "Error:\n  TODO but got {:?} in {}{}",
-                        token.kind, p.filepath, token.loc
-                    );
-                    exit(-1);
-                }
-                let sum = value.pop().unwrap() * value.pop().unwrap();
-                value.push(sum);
-            }
-            Ok(token) => {
-                eprintln!(
-                    "Error:\n  Expect Interger, `+` of `*` but got {:?} in {}{}",
-                    token.kind, p.filepath, token.loc
-                );
-                exit(-1);
-            }
-            Err(e) => {
-                eprintln!("Error:\n  Expect `:` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
-            }
-        }
-    }
-
-    match p.require() {
-        Ok(token) if token == *"=" => {}
+fn parse_const_expr(p: &mut Parser, doc: Option<String>) -> ParseResult<OperationKind> {
+    let value = parse_const_value(p)?;
+    match p.expect(TokenKind::Word) {
         Ok(token) => {
-            eprintln!(
-                "Error:\n  Expect `=` but got {:?} in {}{}",
-                token.kind, p.filepath, token.loc
-            );
-            exit(-1);
-        }
-        Err(e) => {
-            eprintln!("Error:\n  Expect `=` but got EOF in {}{}", p.filepath, e);
-            exit(-1);
+            p.consts.insert(token.value.to_string(), value);
+            Ok(OperationKind::Const(token.value.to_string(), value, doc))
         }
+        Err(e) => Err(p.error(e, "Expect a Word")),
     }
+}
 
+fn parse_alloc_expr(p: &mut Parser, doc: Option<String>) -> ParseResult<OperationKind> {
+    let value = parse_const_value(p)?;
     match p.expect(TokenKind::Word) {
-        Ok(token) => Operation::Alloc(token.value, value[0]),
-        Err(e) => {
-            eprintln!("Error:\n  Expect a Word in {}{}", p.filepath, e);
-            exit(-1);
-        }
+        Ok(token) => Ok(OperationKind::Alloc(token.value.to_string(), value, doc)),
+        Err(e) => Err(p.error(e, "Expect a Word")),
     }
 }
 
-fn parse_assing_expr(p: &mut Parser) -> Operation {
+fn parse_assing_expr(p: &mut Parser) -> ParseResult<OperationKind> {
     let mut type_: Vec<String> = vec![];
     loop {
         match p.require() {
             Ok(token) if token == *"=" => break,
-            Ok(token) if token.kind == TokenKind::Word => type_.push(token.value),
+            Ok(token) if token.kind == TokenKind::Word => type_.push(token.value.to_string()),
             Ok(token) => {
-                eprintln!(
-                    "Error:\n  Expect Type but got `{}` in {}{}",
-                    token.value, p.filepath, token.loc
-                );
-                exit(-1);
-            }
-            Err(e) => {
-                eprintln!("Error:\n  Expect `=` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
+                return Err(p.error(token.loc, format!("Expect Type but got `{}`", token.value)))
             }
+            Err(e) => return Err(p.error(e, "Expect `=` but got EOF")),
         }
     }
     match p.expect(TokenKind::Word) {
-        Ok(token) => Operation::Assing(token.value, type_.into()),
-        Err(e) => {
-            eprintln!("Error:\n  Expect a Word in {}{}", p.filepath, e);
-            exit(-1);
-        }
+        Ok(token) => Ok(OperationKind::Assing(token.value.to_string(), type_.into())),
+        Err(e) => Err(p.error(e, "Expect a Word")),
     }
 }
 
-fn parse_while_expr(p: &mut Parser) -> Operation {
+fn parse_while_expr(p: &mut Parser) -> ParseResult<OperationKind> {
     let mut cond: Vec<Operation> = vec![];
     loop {
         match p.require() {
             Ok(token) if token.kind == TokenKind::OpenCurly => break,
-            Ok(token) => cond.push(parse_expr(p, token)),
-            Err(e) => {
-                eprintln!("Error:\n  Expect `{{` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
-            }
-        }
-    }
-    let mut body: Vec<Operation> = vec![];
-    loop {
-        match p.require() {
-            Ok(token) if token.kind == TokenKind::CloseCurly => break,
-            Ok(token) => body.push(parse_expr(p, token)),
-            Err(e) => {
-                eprintln!("Error:\n  Expect `}}` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
-            }
+            Ok(token) => cond.push(parse_expr(p, token)?),
+            Err(e) => return Err(p.error(e, "Expect `{` but got EOF")),
         }
     }
-    Operation::While(cond.into(), body.into())
+    let body = parse_body(p)?;
+    Ok(OperationKind::While(cond.into(), body.into()))
 }
 
-fn parse_if_expr(p: &mut Parser) -> Operation {
-    match p.expect(TokenKind::OpenCurly) {
-        Ok(_) => {
-            let mut body: Vec<Operation> = vec![];
-            loop {
-                match p.require() {
-                    Ok(token) if token.kind == TokenKind::CloseCurly => break,
-                    Ok(token) => body.push(parse_expr(p, token)),
-                    Err(e) => {
-                        eprintln!("Error:\n  Expect `}}` but got EOF in {}{}", p.filepath, e);
-                        exit(-1);
-                    }
-                }
-            }
-            if p.peek() == "else" {
-                p.next();
-                match p.expect(TokenKind::OpenCurly) {
-                    Ok(_) => {
-                        let mut elsebody: Vec<Operation> = vec![];
-                        loop {
-                            match p.require() {
-                                Ok(token) if token.kind == TokenKind::CloseCurly => break,
-                                Ok(token) => elsebody.push(parse_expr(p, token)),
-                                Err(e) => {
-                                    eprintln!(
-                                        "Error:\n  Expect `}}` but got EOF in {}{}",
-                                        p.filepath, e
-                                    );
-                                    exit(-1);
-                                }
-                            }
-                        }
-                        return Operation::IfElse(body.into(), elsebody.into());
-                    }
-                    Err(e) => {
-                        eprintln!("Error:\n  Expect `{{` after `else` in {}{}", p.filepath, e);
-                        exit(-1)
-                    }
-                }
-            }
-            Operation::If(body.into())
-        }
-        Err(e) => {
-            eprintln!("Error:\n  Expect `{{` after `if` in {}{}", p.filepath, e);
-            exit(-1)
-        }
+fn parse_if_expr(p: &mut Parser) -> ParseResult<OperationKind> {
+    if let Err(e) = p.expect(TokenKind::OpenCurly) {
+        return Err(p.error(e, "Expect `{` after `if`"));
+    }
+    let body = parse_body(p)?;
+    if p.peek() == "else" {
+        p.next();
+        if let Err(e) = p.expect(TokenKind::OpenCurly) {
+            return Err(p.error(e, "Expect `{` after `else`"));
+        }
+        let elsebody = parse_body(p)?;
+        return Ok(OperationKind::IfElse(body.into(), elsebody.into()));
     }
+    Ok(OperationKind::If(body.into()))
 }
 
-fn parse_bind_expr(p: &mut Parser) -> Operation {
+fn parse_bind_expr(p: &mut Parser) -> ParseResult<OperationKind> {
     match p.expect(TokenKind::Interger) {
-        Ok(token) => {
-            let val = token
-                .value
-                .parse()
-                .map_err(|e| eprintln!("ParseIntError: {} in {:?}", e, token))
-                .expect("ParseInt");
-            Operation::Bind(val)
-        }
-        Err(e) => {
-            eprintln!("Error:\n  Expect `{{` after `else` in {}{}", p.filepath, e);
-            exit(-1)
-        }
+        Ok(token) => Ok(OperationKind::Bind(parse_int(p, &token)?)),
+        Err(e) => Err(p.error(e, "Expect index after `&`")),
     }
 }
 
-fn parse_sys_expr(p: &mut Parser) -> Operation {
+fn parse_sys_expr(p: &mut Parser) -> ParseResult<OperationKind> {
     match p.expect(TokenKind::Word) {
-        Ok(token) => match token.value.as_str() {
-            "write" => Operation::Sys(token.value),
-            _ => {
-                eprintln!(
-                    "Error:\n  Unexpect Word `{}` after `$` in {}{}",
-                    token.value, p.filepath, token.loc
-                );
-                exit(-1)
-            }
+        Ok(token) => match Syscall::from_name(token.value) {
+            Some(call) => Ok(OperationKind::Sys(call)),
+            None => Err(p.error(
+                token.loc,
+                format!("Unexpect Word `{}` after `$`", token.value),
+            )),
         },
-        Err(e) => {
-            eprintln!("Error:\n  Expect `{{` after `else` in {}{}", p.filepath, e);
-            exit(-1)
-        }
+        Err(e) => Err(p.error(e, "Expect a Word after `$`")),
     }
 }
 
-fn parse_read_expr(p: &mut Parser) -> Operation {
+fn parse_read_expr(p: &mut Parser) -> ParseResult<OperationKind> {
     match p.expect(TokenKind::Interger) {
-        Ok(token) => {
-            let val = token
-                .value
-                .parse()
-                .map_err(|e| eprintln!("ParseIntError: {} in {:?}", e, token))
-                .expect("ParseInt");
-            Operation::Read(val)
-        }
-        Err(e) => {
-            eprintln!(
-                "Error:\n  Expect number of bytes after `@` in {}{}",
-                p.filepath, e
-            );
-            exit(-1)
-        }
+        Ok(token) => Ok(OperationKind::Read(parse_int(p, &token)?)),
+        Err(e) => Err(p.error(e, "Expect number of bytes after `@`")),
     }
 }
 
-fn parse_write_expr(p: &mut Parser) -> Operation {
+fn parse_write_expr(p: &mut Parser) -> ParseResult<OperationKind> {
     match p.expect(TokenKind::Interger) {
-        Ok(token) => {
-            let val = token
-                .value
-                .parse()
-                .map_err(|e| eprintln!("ParseIntError: {} in {:?}", e, token))
-                .expect("ParseInt");
-            Operation::Write(val)
-        }
-        Err(e) => {
-            eprintln!(
-                "Error:\n  Expect number of bytes `!` in {}{}",
-                p.filepath, e
-            );
-            exit(-1)
-        }
+        Ok(token) => Ok(OperationKind::Write(parse_int(p, &token)?)),
+        Err(e) => Err(p.error(e, "Expect number of bytes `!`")),
     }
 }
 
-fn parse_let_expr(p: &mut Parser) -> Operation {
+fn parse_let_expr(p: &mut Parser) -> ParseResult<OperationKind> {
     let mut names: Vec<String> = vec![];
     loop {
         match p.require() {
             Ok(token) if token == *"{" => break,
-            Ok(token) if token.kind == TokenKind::Word => names.push(token.value),
+            Ok(token) if token.kind == TokenKind::Word => names.push(token.value.to_string()),
             Ok(token) => {
-                eprintln!(
-                    "Error:\n  Expect Word but got `{}` in {}{}",
-                    token.value, p.filepath, token.loc
-                );
-                exit(-1);
-            }
-            Err(e) => {
-                eprintln!("Error:\n  Expect `{{` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
+                return Err(p.error(token.loc, format!("Expect Word but got `{}`", token.value)))
             }
+            Err(e) => return Err(p.error(e, "Expect `{` but got EOF")),
         }
     }
 
-    let mut body: Vec<Operation> = vec![];
-    loop {
-        match p.require() {
-            Ok(token) if token == *"}" => break,
-            Ok(token) => body.push(parse_expr(p, token)),
-            Err(e) => {
-                eprintln!("Error:\n  Expect `}}` but got EOF in {}{}", p.filepath, e);
-                exit(-1);
-            }
-        }
-    }
+    let body = parse_body(p)?;
+    Ok(OperationKind::Let(names.into(), body.into()))
+}
 
-    Operation::Let(names.into(), body.into())
+fn parse_int<T: FromStr>(p: &mut Parser, token: &Token) -> ParseResult<T>
+where
+    T::Err: fmt::Display,
+{
+    token.value.parse().map_err(|e| {
+        p.error(
+            token.loc,
+            format!("Invalid integer `{}`: {}", token.value, e),
+        )
+    })
 }
 
-fn parse_expr(p: &mut Parser, token: Token) -> Operation {
+fn parse_expr(p: &mut Parser, token: Token) -> ParseResult<Operation> {
     //dbg!(&token);
-    match token.kind {
+    let kind = match token.kind {
         TokenKind::KeyWord if token == *"if" => parse_if_expr(p),
         TokenKind::KeyWord if token == *"while" => parse_while_expr(p),
         TokenKind::KeyWord if token == *":" => parse_assing_expr(p),
@@ -540,39 +385,57 @@ fn parse_expr(p: &mut Parser, token: Token) -> Operation {
         TokenKind::KeyWord if token == *"$" => parse_sys_expr(p),
         TokenKind::Intrinsic if token == *"@" => parse_read_expr(p),
         TokenKind::Intrinsic if token == *"!" => parse_write_expr(p),
-        TokenKind::String => Operation::Str(token.value),
-        TokenKind::Interger => {
-            let val = token
-                .value
-                .parse()
-                .map_err(|e| eprintln!("ParseIntError: {} in {:?}", e, token))
-                .expect("ParseInt");
-            Operation::PushI(val)
-        }
-        TokenKind::KeyWord if token == *"debug" => Operation::Debug,
-        TokenKind::Intrinsic => Operation::Intrinsic(token.value),
-        TokenKind::Word => Operation::Word(token.value),
+        TokenKind::String => Ok(OperationKind::Str(token.unescape().into_owned())),
+        TokenKind::Interger => Ok(OperationKind::PushI(parse_int(p, &token)?)),
+        TokenKind::KeyWord if token == *"debug" => Ok(OperationKind::Debug),
+        TokenKind::KeyWord if token == *"true" => Ok(OperationKind::PushBool(true)),
+        TokenKind::KeyWord if token == *"false" => Ok(OperationKind::PushBool(false)),
+        TokenKind::Intrinsic => Ok(OperationKind::Intrinsic(token.value.to_string(), None)),
+        TokenKind::Word => match DataType::from_name(token.value) {
+            Some(typ) => Ok(OperationKind::Cast(typ)),
+            None => Ok(OperationKind::Word(token.value.to_string())),
+        },
         TokenKind::KeyWord => {
-            eprintln!(
-                "Error:\n  Unexpect KeyWord `{}` in {}{}",
-                token.value, p.filepath, token.loc
-            );
-            exit(-1)
+            Err(p.error(token.loc, format!("Unexpect KeyWord `{}`", token.value)))
         }
-        _ => {
-            unimplemented!(
-                "Error:\n  Unimplemented {:?} `{}` in {}{}",
-                token.kind,
-                token.value,
-                p.filepath,
-                token.loc
-            );
+        TokenKind::Invalid if token.value.starts_with("{-") => {
+            Err(p.error(token.loc, "Unterminated block comment"))
         }
-    }
+        _ => Err(p.error(token.loc, format!("Unexpect `{}`", token.value))),
+    }?;
+    Ok(Operation::new(kind, token.loc))
 }
 
-impl Parser {
-    fn expect(&mut self, kind: TokenKind) -> Result<Token, Loc> {
+impl<'src> Parser<'src> {
+    fn new(
+        tokens: Box<dyn TokenSource<'src> + 'src>,
+        filepath: &str,
+        consts: HashMap<String, usize>,
+    ) -> Self {
+        Self {
+            tokens,
+            filepath: filepath.to_string(),
+            peeked: None,
+            doc: None,
+            end: 0,
+            read: Span::default(),
+            consts,
+        }
+    }
+
+    fn error(&self, loc: Loc, message: impl Into<String>) -> ParseError {
+        ParseError {
+            filepath: self.filepath.clone(),
+            loc,
+            message: message.into(),
+        }
+    }
+
+    fn loc(&mut self) -> Loc {
+        self.peek().loc
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Token<'src>, Loc> {
         let token = self.next();
 
         if token.kind == kind {
@@ -581,29 +444,41 @@ impl Parser {
         Err(token.loc)
     }
 
-    #[allow(dead_code)]
-    fn peek(&mut self) -> &Token {
+    fn peek(&mut self) -> &Token<'src> {
         if self.peeked.is_none() {
-            self.peeked = Some(self.next());
+            self.peeked = Some(self.read_token());
         }
 
         self.peeked.as_ref().unwrap()
     }
 
-    fn next(&mut self) -> Token {
-        loop {
-            let token = self
-                .peeked
-                .take()
-                .unwrap_or_else(|| self.lexer.next_token());
+    fn next(&mut self) -> Token<'src> {
+        let token = match self.peeked.take() {
+            Some(token) => token,
+            None => self.read_token(),
+        };
+        self.end = token.span.end;
+        token
+    }
 
+    fn read_token(&mut self) -> Token<'src> {
+        let mut doc: Vec<&str> = vec![];
+        loop {
+            let token = self.tokens.next_token();
             match token.kind {
-                TokenKind::Whitespace | TokenKind::Comment => {}
-                _ => return token,
+                TokenKind::DocComment => {
+                    let line = token.value.trim_start_matches('-');
+                    doc.push(line.strip_prefix(' ').unwrap_or(line).trim_end());
+                }
+                _ => {
+                    self.doc = (!doc.is_empty()).then(|| doc.join("\n"));
+                    self.read = token.span;
+                    return token;
+                }
             }
         }
     }
-    fn require(&mut self) -> Result<Token, Loc> {
+    fn require(&mut self) -> Result<Token<'src>, Loc> {
         let tok = self.next();
         if matches!(tok.kind, TokenKind::EOF) {
             return Err(tok.loc);
@@ -614,33 +489,132 @@ impl Parser {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DataType {
-    Int,
+    Int, // i64
     Ptr,
     Bool,
+    Char,
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
 }
 
+impl DataType {
+    /// Type named `name` in signatures and cast words. `i64` is `int`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let typ = match name {
+            "int" | "i64" => DataType::Int,
+            "ptr" => DataType::Ptr,
+            "bool" => DataType::Bool,
+            "char" => DataType::Char,
+            "u8" => DataType::U8,
+            "u16" => DataType::U16,
+            "u32" => DataType::U32,
+            "u64" => DataType::U64,
+            "i8" => DataType::I8,
+            "i16" => DataType::I16,
+            "i32" => DataType::I32,
+            _ => return None,
+        };
+        Some(typ)
+    }
+    pub fn is_integer(&self) -> bool {
+        self.bits().is_some() && *self != DataType::Char
+    }
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            DataType::Int | DataType::I8 | DataType::I16 | DataType::I32
+        )
+    }
+    /// Width of integers and `char`, whose values are unicode scalars.
+    pub fn bits(&self) -> Option<u32> {
+        match self {
+            DataType::U8 | DataType::I8 => Some(8),
+            DataType::U16 | DataType::I16 => Some(16),
+            DataType::U32 | DataType::I32 | DataType::Char => Some(32),
+            DataType::Int | DataType::U64 => Some(64),
+            DataType::Ptr | DataType::Bool => None,
+        }
+    }
+}
+
+/// Types a function takes from the stack and leaves on it, the last one on
+/// top.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub ins: Rc<[DataType]>,
+    pub outs: Rc<[DataType]>,
+}
+
+impl Signature {
+    pub fn new(ins: &[DataType], outs: &[DataType]) -> Self {
+        Self {
+            ins: ins.into(),
+            outs: outs.into(),
+        }
+    }
+}
+
+/// An operation and the location of the token it starts at.
 #[derive(Debug, Clone)]
-pub enum Operation {
+pub struct Operation {
+    pub kind: OperationKind,
+    pub loc: Loc,
+}
+
+impl Operation {
+    pub fn new(kind: OperationKind, loc: Loc) -> Self {
+        Self { kind, loc }
+    }
+    /// Doc comment attached to a `fn`, `const` or `alloc` definition.
+    pub fn doc(&self) -> Option<&str> {
+        self.kind.doc()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum OperationKind {
     Debug,
-    Sys(String),                    // SysFnName
-    Str(String),                    // String
-    Alloc(String, usize),           // Name Size
-    Read(usize),                    // Bytes
-    Write(usize),                   // Bytes
-    Word(String),                   // Word
-    Intrinsic(String),              // Symbol
-    PushI(i32),                     // Literal
-    If(Rc<[Self]>),                 // Body
-    IfElse(Rc<[Self]>, Rc<[Self]>), // Body1 Body2
-    While(Rc<[Self]>, Rc<[Self]>),  // cond Body
-    Bind(u32),                      // index
-    Assing(String, Rc<[String]>),   // name type
-    Let(Rc<[String]>, Rc<[Self]>),  // names Body
+    Sys(Syscall),
+    Str(String),                              // String
+    Const(String, usize, Option<String>),     // Name Value Doc
+    Alloc(String, usize, Option<String>),     // Name Size Doc
+    Read(usize),                              // Bytes
+    Write(usize),                             // Bytes
+    Word(String),                             // Word
+    Intrinsic(String, Option<DataType>),      // Symbol OperandType (set by type_check)
+    Cast(DataType),                           // Type
+    PushI(i32),                               // Literal
+    PushBool(bool),                           // Literal
+    If(Rc<[Operation]>),                      // Body
+    IfElse(Rc<[Operation]>, Rc<[Operation]>), // Body1 Body2
+    While(Rc<[Operation]>, Rc<[Operation]>),  // cond Body
+    Bind(u32),                                // index
+    Assing(String, Rc<[String]>),             // name type
+    Let(Rc<[String]>, Rc<[Operation]>),       // names Body
     Fn(
         String,
         Rc<[String]>,
         Rc<[DataType]>,
         Rc<[DataType]>,
-        Rc<[Self]>,
-    ), // name args ins outs body
+        Rc<[Operation]>,
+        Option<String>,
+        bool,
+    ), // name args ins outs body doc noinline
+}
+
+impl OperationKind {
+    /// Doc comment attached to a `fn`, `const` or `alloc` definition.
+    pub fn doc(&self) -> Option<&str> {
+        match self {
+            OperationKind::Const(.., doc)
+            | OperationKind::Alloc(.., doc)
+            | OperationKind::Fn(.., doc, _) => doc.as_deref(),
+            _ => None,
+        }
+    }
 }
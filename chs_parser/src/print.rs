@@ -0,0 +1,297 @@
+//! Turning `Operation`s back into text: chs source through `Display`, and a
+//! S-expression dump for tools and golden tests.
+use std::fmt::{self, Write};
+
+use crate::{DataType, Operation, OperationKind};
+
+const INDENT: &str = "    ";
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DataType::Int => "int",
+            DataType::Ptr => "ptr",
+            DataType::Bool => "bool",
+            DataType::Char => "char",
+            DataType::U8 => "u8",
+            DataType::U16 => "u16",
+            DataType::U32 => "u32",
+            DataType::U64 => "u64",
+            DataType::I8 => "i8",
+            DataType::I16 => "i16",
+            DataType::I32 => "i32",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Canonical source of the operation. Blocks are written on one line, or
+/// one line per operation with `{:#}`.
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pretty = f.alternate();
+        write_op(f, self, 0, pretty)
+    }
+}
+
+/// Source of a whole program, parsing back to the same operations.
+pub fn to_source(ops: &[Operation]) -> String {
+    let mut out = String::new();
+    let _ = write_ops(&mut out, ops, 0, true);
+    if !ops.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+/// Operations that are written on their own line in pretty output.
+fn own_line(op: &Operation) -> bool {
+    op.doc().is_some()
+        || matches!(
+            op.kind,
+            OperationKind::If(..)
+                | OperationKind::IfElse(..)
+                | OperationKind::While(..)
+                | OperationKind::Let(..)
+                | OperationKind::Fn(..)
+                | OperationKind::Const(..)
+                | OperationKind::Alloc(..)
+        )
+}
+
+fn newline(f: &mut impl Write, depth: usize) -> fmt::Result {
+    writeln!(f)?;
+    for _ in 0..depth {
+        f.write_str(INDENT)?;
+    }
+    Ok(())
+}
+
+fn write_ops(f: &mut impl Write, ops: &[Operation], depth: usize, pretty: bool) -> fmt::Result {
+    for (i, op) in ops.iter().enumerate() {
+        if i > 0 {
+            if pretty && (own_line(op) || own_line(&ops[i - 1])) {
+                newline(f, depth)?;
+            } else {
+                f.write_char(' ')?;
+            }
+        }
+        write_op(f, op, depth, pretty)?;
+    }
+    Ok(())
+}
+
+fn write_block(f: &mut impl Write, ops: &[Operation], depth: usize, pretty: bool) -> fmt::Result {
+    if ops.is_empty() {
+        return f.write_str("{}");
+    }
+    f.write_char('{')?;
+    if pretty {
+        newline(f, depth + 1)?;
+        write_ops(f, ops, depth + 1, pretty)?;
+        newline(f, depth)?;
+    } else {
+        f.write_char(' ')?;
+        write_ops(f, ops, depth, pretty)?;
+        f.write_char(' ')?;
+    }
+    f.write_char('}')
+}
+
+fn write_op(f: &mut impl Write, op: &Operation, depth: usize, pretty: bool) -> fmt::Result {
+    if let Some(doc) = op.doc() {
+        for line in doc.lines() {
+            match line {
+                "" => f.write_str("---")?,
+                _ => write!(f, "--- {}", line)?,
+            }
+            newline(f, depth)?;
+        }
+    }
+    match &op.kind {
+        OperationKind::Debug => f.write_str("debug"),
+        OperationKind::Sys(call) => write!(f, "${}", call),
+        OperationKind::Str(s) => {
+            f.write_char('"')?;
+            for c in s.chars() {
+                match c {
+                    '\n' => f.write_str("\\n")?,
+                    '\\' => f.write_str("\\\\")?,
+                    _ => f.write_char(c)?,
+                }
+            }
+            f.write_char('"')
+        }
+        OperationKind::Const(name, value, _) => write!(f, "const {} := {}", value, name),
+        OperationKind::Alloc(name, size, _) => write!(f, "alloc {} := {}", size, name),
+        OperationKind::Read(bytes) => write!(f, "@{}", bytes),
+        OperationKind::Write(bytes) => write!(f, "!{}", bytes),
+        OperationKind::Word(word) => f.write_str(word),
+        OperationKind::Intrinsic(symbol, _) => f.write_str(symbol),
+        OperationKind::Cast(typ) => write!(f, "{}", typ),
+        OperationKind::PushI(value) => write!(f, "{}", value),
+        OperationKind::PushBool(value) => write!(f, "{}", value),
+        OperationKind::If(body) => {
+            f.write_str("if ")?;
+            write_block(f, body, depth, pretty)
+        }
+        OperationKind::IfElse(body, elsebody) => {
+            f.write_str("if ")?;
+            write_block(f, body, depth, pretty)?;
+            f.write_str(" else ")?;
+            write_block(f, elsebody, depth, pretty)
+        }
+        OperationKind::While(cond, body) => {
+            f.write_str("while ")?;
+            // The condition ends at `{`, so it is always on one line.
+            write_ops(f, cond, depth, false)?;
+            if !cond.is_empty() {
+                f.write_char(' ')?;
+            }
+            write_block(f, body, depth, pretty)
+        }
+        OperationKind::Bind(index) => write!(f, "&{}", index),
+        OperationKind::Assing(name, types) => {
+            f.write_char(':')?;
+            for typ in types.iter() {
+                write!(f, " {}", typ)?;
+            }
+            write!(f, " = {}", name)
+        }
+        OperationKind::Let(names, body) => {
+            f.write_str("let")?;
+            for name in names.iter() {
+                write!(f, " {}", name)?;
+            }
+            f.write_char(' ')?;
+            write_block(f, body, depth, pretty)
+        }
+        OperationKind::Fn(name, args, ins, outs, body, _, noinline) => {
+            write!(f, "fn {}", name)?;
+            for arg in args.iter() {
+                write!(f, " {}", arg)?;
+            }
+            f.write_str(" :")?;
+            for typ in ins.iter() {
+                write!(f, " {}", typ)?;
+            }
+            f.write_str(" ->")?;
+            for typ in outs.iter() {
+                write!(f, " {}", typ)?;
+            }
+            if *noinline {
+                f.write_str(" noinline")?;
+            }
+            f.write_char(' ')?;
+            write_block(f, body, depth, pretty)
+        }
+    }
+}
+
+/// S-expression of an operation, e.g. `(fn "sq" () (int) (int) ((intrinsic
+/// "dup") (intrinsic "*")))`. Names and strings are always quoted; a doc
+/// comment is added at the end as `(doc "...")`.
+pub struct Sexp<'a>(pub &'a Operation);
+
+impl fmt::Display for Sexp<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_sexp(f, self.0)
+    }
+}
+
+/// S-expressions of a whole program, one top-level operation per line.
+pub fn to_sexp(ops: &[Operation]) -> String {
+    let mut out = String::new();
+    for op in ops {
+        let _ = writeln!(out, "{}", Sexp(op));
+    }
+    out
+}
+
+fn write_list<T>(
+    f: &mut dyn Write,
+    items: &[T],
+    mut write: impl FnMut(&mut dyn Write, &T) -> fmt::Result,
+) -> fmt::Result {
+    f.write_char('(')?;
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_char(' ')?;
+        }
+        write(f, item)?;
+    }
+    f.write_char(')')
+}
+
+fn write_sexps(f: &mut dyn Write, ops: &[Operation]) -> fmt::Result {
+    write_list(f, ops, |f, op| write_sexp(f, op))
+}
+
+fn write_names(f: &mut dyn Write, names: &[String]) -> fmt::Result {
+    write_list(f, names, |f, name| write!(f, "{:?}", name))
+}
+
+fn write_types(f: &mut dyn Write, types: &[DataType]) -> fmt::Result {
+    write_list(f, types, |f, typ| write!(f, "{}", typ))
+}
+
+fn write_sexp(f: &mut dyn Write, op: &Operation) -> fmt::Result {
+    match &op.kind {
+        OperationKind::Debug => f.write_str("(debug")?,
+        OperationKind::Sys(call) => write!(f, "(sys {:?}", call.name())?,
+        OperationKind::Str(s) => write!(f, "(str {:?}", s)?,
+        OperationKind::Const(name, value, _) => write!(f, "(const {:?} {}", name, value)?,
+        OperationKind::Alloc(name, size, _) => write!(f, "(alloc {:?} {}", name, size)?,
+        OperationKind::Read(bytes) => write!(f, "(read {}", bytes)?,
+        OperationKind::Write(bytes) => write!(f, "(write {}", bytes)?,
+        OperationKind::Word(word) => write!(f, "(word {:?}", word)?,
+        OperationKind::Intrinsic(symbol, _) => write!(f, "(intrinsic {:?}", symbol)?,
+        OperationKind::Cast(typ) => write!(f, "(cast {}", typ)?,
+        OperationKind::PushI(value) => write!(f, "(int {}", value)?,
+        OperationKind::PushBool(value) => write!(f, "(bool {}", value)?,
+        OperationKind::If(body) => {
+            f.write_str("(if ")?;
+            write_sexps(f, body)?;
+        }
+        OperationKind::IfElse(body, elsebody) => {
+            f.write_str("(if-else ")?;
+            write_sexps(f, body)?;
+            f.write_char(' ')?;
+            write_sexps(f, elsebody)?;
+        }
+        OperationKind::While(cond, body) => {
+            f.write_str("(while ")?;
+            write_sexps(f, cond)?;
+            f.write_char(' ')?;
+            write_sexps(f, body)?;
+        }
+        OperationKind::Bind(index) => write!(f, "(bind {}", index)?,
+        OperationKind::Assing(name, types) => {
+            write!(f, "(assign {:?} ", name)?;
+            write_names(f, types)?;
+        }
+        OperationKind::Let(names, body) => {
+            f.write_str("(let ")?;
+            write_names(f, names)?;
+            f.write_char(' ')?;
+            write_sexps(f, body)?;
+        }
+        OperationKind::Fn(name, args, ins, outs, body, _, noinline) => {
+            write!(f, "(fn {:?} ", name)?;
+            write_names(f, args)?;
+            f.write_char(' ')?;
+            write_types(f, ins)?;
+            f.write_char(' ')?;
+            write_types(f, outs)?;
+            f.write_char(' ')?;
+            write_sexps(f, body)?;
+            if *noinline {
+                f.write_str(" (noinline)")?;
+            }
+        }
+    }
+    if let Some(doc) = op.doc() {
+        write!(f, " (doc {:?})", doc)?;
+    }
+    f.write_char(')')
+}
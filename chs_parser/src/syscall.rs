@@ -0,0 +1,94 @@
+//! The `$name` system calls. Their names and stack signatures are defined once
+//! here, for the parser, the type checker and the VM.
+use std::fmt;
+
+use crate::{DataType, Signature};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Syscall {
+    Write,
+    Read,
+    Open,
+    Close,
+    Exit,
+    Argc,
+    Argv,
+    Getenv,
+    Clock,
+}
+
+use DataType::{Int, Ptr};
+
+type Entry = (
+    Syscall,
+    &'static str,
+    &'static [DataType],
+    &'static [DataType],
+);
+
+/// Name, arguments and results of each syscall, the last argument on top:
+///
+/// - `write` (len ptr fd ->) writes `len` bytes at `ptr` to `fd`.
+/// - `read` (len ptr fd -> n) reads up to `len` bytes from `fd` to `ptr`.
+///   `n` is the number of bytes read, 0 at the end, or -1 on error.
+/// - `open` (len ptr flags -> fd) opens the file named by the `len` bytes at
+///   `ptr`. `flags` is a sum of `OPEN_*`; `fd` is -1 on error.
+/// - `close` (fd -> ok) is 0 when `fd` was open and -1 otherwise.
+/// - `exit` (code ->) ends the program with exit code `code`.
+/// - `argc` (-> n) is the number of program arguments.
+/// - `argv` (len ptr i -> n) copies up to `len` bytes of argument `i` to `ptr`.
+///   `n` is its full length, or -1 when there is no such argument.
+/// - `getenv` (len ptr buflen buf -> n) copies up to `buflen` bytes of the
+///   environment variable named by `len` `ptr` to `buf`. `n` is the full
+///   length of its value, or -1 when it is not set.
+/// - `clock` (-> ns) is the time in nanoseconds on a clock that never goes
+///   back, from the start of the program.
+const TABLE: [Entry; 9] = [
+    (Syscall::Write, "write", &[Int, Ptr, Int], &[]),
+    (Syscall::Read, "read", &[Int, Ptr, Int], &[Int]),
+    (Syscall::Open, "open", &[Int, Ptr, Int], &[Int]),
+    (Syscall::Close, "close", &[Int], &[Int]),
+    (Syscall::Exit, "exit", &[Int], &[]),
+    (Syscall::Argc, "argc", &[], &[Int]),
+    (Syscall::Argv, "argv", &[Int, Ptr, Int], &[Int]),
+    (Syscall::Getenv, "getenv", &[Int, Ptr, Int, Ptr], &[Int]),
+    (Syscall::Clock, "clock", &[], &[Int]),
+];
+
+/// `open` flags.
+pub const OPEN_READ: i64 = 1;
+pub const OPEN_WRITE: i64 = 2;
+pub const OPEN_CREATE: i64 = 4;
+pub const OPEN_TRUNCATE: i64 = 8;
+pub const OPEN_APPEND: i64 = 16;
+
+impl Syscall {
+    pub fn all() -> impl Iterator<Item = Syscall> {
+        TABLE.iter().map(|entry| entry.0)
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        TABLE
+            .iter()
+            .find(|entry| entry.1 == name)
+            .map(|entry| entry.0)
+    }
+    fn entry(self) -> &'static Entry {
+        TABLE
+            .iter()
+            .find(|entry| entry.0 == self)
+            .expect("every syscall is in the table")
+    }
+    pub fn name(self) -> &'static str {
+        self.entry().1
+    }
+    pub fn signature(self) -> Signature {
+        let (_, _, ins, outs) = self.entry();
+        Signature::new(ins, outs)
+    }
+}
+
+impl fmt::Display for Syscall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
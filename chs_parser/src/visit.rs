@@ -0,0 +1,149 @@
+//! Traversal of `Operation` trees.
+//!
+//! Each trait has a method per level of the tree whose default goes on into
+//! the blocks of `if`, `if ... else`, `while`, `let` and `fn`, through the
+//! `walk_*`/`fold_*` function of the same name. A pass overrides the method
+//! for what it cares about and calls the function to keep going:
+//!
+//! ```
+//! use chs_parser::{parse_program, visit::walk_op, Operation, OperationKind, Visitor};
+//!
+//! #[derive(Default)]
+//! struct Words(Vec<String>);
+//!
+//! impl Visitor for Words {
+//!     fn visit_op(&mut self, op: &Operation) {
+//!         if let OperationKind::Word(word) = &op.kind {
+//!             self.0.push(word.clone());
+//!         }
+//!         walk_op(self, op);
+//!     }
+//! }
+//!
+//! let program = parse_program("fn f : -> { a if { b } } c", "words.chs").unwrap();
+//! let mut words = Words::default();
+//! words.visit_ops(&program);
+//! assert_eq!(words.0, ["a", "b", "c"]);
+//! ```
+use std::rc::Rc;
+
+use crate::{Operation, OperationKind};
+
+/// Looks at operations by reference.
+pub trait Visitor: Sized {
+    fn visit_ops(&mut self, ops: &[Operation]) {
+        walk_ops(self, ops)
+    }
+    fn visit_op(&mut self, op: &Operation) {
+        walk_op(self, op)
+    }
+}
+
+pub fn walk_ops<V: Visitor>(v: &mut V, ops: &[Operation]) {
+    for op in ops {
+        v.visit_op(op);
+    }
+}
+
+/// Visit the blocks of `op`, in source order.
+pub fn walk_op<V: Visitor>(v: &mut V, op: &Operation) {
+    match &op.kind {
+        OperationKind::If(body)
+        | OperationKind::Let(_, body)
+        | OperationKind::Fn(.., body, _, _) => v.visit_ops(body),
+        OperationKind::IfElse(body, elsebody) => {
+            v.visit_ops(body);
+            v.visit_ops(elsebody);
+        }
+        OperationKind::While(cond, body) => {
+            v.visit_ops(cond);
+            v.visit_ops(body);
+        }
+        _ => {}
+    }
+}
+
+/// Changes operations in place. Blocks shared with other trees are copied
+/// before they are changed.
+pub trait MutVisitor: Sized {
+    fn visit_ops_mut(&mut self, ops: &mut [Operation]) {
+        walk_ops_mut(self, ops)
+    }
+    fn visit_op_mut(&mut self, op: &mut Operation) {
+        walk_op_mut(self, op)
+    }
+}
+
+pub fn walk_ops_mut<V: MutVisitor>(v: &mut V, ops: &mut [Operation]) {
+    for op in ops {
+        v.visit_op_mut(op);
+    }
+}
+
+pub fn walk_op_mut<V: MutVisitor>(v: &mut V, op: &mut Operation) {
+    match &mut op.kind {
+        OperationKind::If(body)
+        | OperationKind::Let(_, body)
+        | OperationKind::Fn(.., body, _, _) => v.visit_ops_mut(make_mut(body)),
+        OperationKind::IfElse(body, elsebody) => {
+            v.visit_ops_mut(make_mut(body));
+            v.visit_ops_mut(make_mut(elsebody));
+        }
+        OperationKind::While(cond, body) => {
+            v.visit_ops_mut(make_mut(cond));
+            v.visit_ops_mut(make_mut(body));
+        }
+        _ => {}
+    }
+}
+
+/// Operations of a block to change in place, copied first if the block is
+/// shared.
+pub fn make_mut(ops: &mut Rc<[Operation]>) -> &mut [Operation] {
+    if Rc::get_mut(ops).is_none() {
+        *ops = ops.iter().cloned().collect();
+    }
+    Rc::get_mut(ops).expect("just made unique")
+}
+
+/// Rebuilds operations by value. Unlike `MutVisitor` it can remove, replace
+/// or add operations in a block.
+pub trait Fold: Sized {
+    fn fold_ops(&mut self, ops: Vec<Operation>) -> Vec<Operation> {
+        fold_ops(self, ops)
+    }
+    fn fold_op(&mut self, op: Operation) -> Operation {
+        fold_op(self, op)
+    }
+}
+
+pub fn fold_ops<F: Fold>(f: &mut F, ops: Vec<Operation>) -> Vec<Operation> {
+    ops.into_iter().map(|op| f.fold_op(op)).collect()
+}
+
+/// The location of `op` is kept.
+pub fn fold_op<F: Fold>(f: &mut F, op: Operation) -> Operation {
+    let kind = match op.kind {
+        OperationKind::If(body) => OperationKind::If(fold_block(f, body)),
+        OperationKind::IfElse(body, elsebody) => {
+            let body = fold_block(f, body);
+            OperationKind::IfElse(body, fold_block(f, elsebody))
+        }
+        OperationKind::While(cond, body) => {
+            let cond = fold_block(f, cond);
+            OperationKind::While(cond, fold_block(f, body))
+        }
+        OperationKind::Let(names, body) => OperationKind::Let(names, fold_block(f, body)),
+        OperationKind::Fn(name, args, ins, outs, body, doc, noinline) => {
+            OperationKind::Fn(name, args, ins, outs, fold_block(f, body), doc, noinline)
+        }
+        kind => kind,
+    };
+    Operation::new(kind, op.loc)
+}
+
+/// The operations of a block are cloned out of it; this is cheap as their own
+/// blocks are shared, not copied.
+fn fold_block<F: Fold>(f: &mut F, ops: Rc<[Operation]>) -> Rc<[Operation]> {
+    f.fold_ops(ops.iter().cloned().collect()).into()
+}
@@ -0,0 +1,144 @@
+//! `Document::edit` gives the same items as parsing the edited source again.
+use std::{fs, path::Path};
+
+use chs_lexer::{Span, TextEdit};
+use chs_parser::{Document, Operation, OperationKind};
+
+/// Text that edits insert, picked to join, split and break definitions.
+const SNIPPETS: &[&str] = &[
+    "",
+    " ",
+    "\n",
+    "1",
+    "18446744073709551615",
+    "x",
+    "fn",
+    "const",
+    "alloc",
+    "let",
+    "if",
+    "else",
+    "while",
+    "{",
+    "}",
+    ":",
+    "->",
+    ":=",
+    "int",
+    "---",
+    "--- doc\n",
+    "-- note\n",
+    "{-",
+    "{- ",
+    "{-}",
+    "-}",
+    "\"",
+    "fn f : -> {",
+    "const 1 := A\n",
+    "dup drop",
+    "é",
+];
+
+/// A xorshift generator, so that a failure is the same on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn sources(dir: &Path, out: &mut Vec<(String, String)>) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            sources(&path, out);
+        } else if path.extension().is_some_and(|ext| ext == "chs") {
+            if let Ok(source) = fs::read_to_string(&path) {
+                out.push((path.display().to_string(), source));
+            }
+        }
+    }
+}
+
+/// A byte of `source` that is on a `char` boundary.
+fn boundary(rng: &mut Rng, source: &str) -> usize {
+    let mut at = rng.below(source.len() + 1);
+    while !source.is_char_boundary(at) {
+        at -= 1;
+    }
+    at
+}
+
+#[test]
+fn edits_match_a_fresh_parse() {
+    let mut files = vec![];
+    sources(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests"),
+        &mut files,
+    );
+    assert!(!files.is_empty());
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for (path, source) in files {
+        let mut doc = Document::new(source.clone(), path.clone());
+        for _ in 0..40 {
+            let source = doc.source().to_string();
+            let start = boundary(&mut rng, &source);
+            let end = start + boundary(&mut rng, &source[start..]).min(12);
+            let end = (start..=end)
+                .rev()
+                .find(|&at| source.is_char_boundary(at))
+                .unwrap();
+            let text = SNIPPETS[rng.below(SNIPPETS.len())];
+            let edit = TextEdit::new(start..end, text);
+            doc.edit(&edit);
+
+            let mut edited = source.clone();
+            edited.replace_range(start..end, text);
+            let fresh = Document::new(edited, path.clone());
+            assert_eq!(
+                format!("{:#?}", doc.items()),
+                format!("{:#?}", fresh.items()),
+                "{} after {:?} on {:?}",
+                path,
+                edit,
+                source
+            );
+        }
+    }
+}
+
+#[test]
+fn doc_comments_and_a_broken_definition_are_one_item() {
+    let mut doc = Document::new(
+        "--- a\n--- b\nconst 1 := 2\n".to_string(),
+        "a.chs".to_string(),
+    );
+    let [item] = doc.items() else {
+        panic!("one item: {:#?}", doc.items());
+    };
+    assert_eq!(item.span, Span::new(0, 24));
+    assert!(item.result.is_err());
+
+    doc.edit(&TextEdit::new(23..24, "X"));
+    let [item] = doc.items() else {
+        panic!("one item: {:#?}", doc.items());
+    };
+    match &item.result {
+        Ok(Operation {
+            kind: OperationKind::Const(name, 1, Some(doc)),
+            ..
+        }) => assert_eq!((name.as_str(), doc.as_str()), ("X", "a\nb")),
+        result => panic!("`const X` with a doc: {:?}", result),
+    }
+}
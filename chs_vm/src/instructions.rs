@@ -103,4 +103,7 @@ impl Bytecode {
     pub fn len(&self) -> usize {
         self.program.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.program.is_empty()
+    }
 }
@@ -1,22 +1,17 @@
 use core::fmt;
 use std::io::{self, Read, Write};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub enum Value {
     Int64(i64),
     Array(Vec<Self>),
     Bool(bool),
     Char(char),
     Ptr(usize),
+    #[default]
     Nil,
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Self::Nil
-    }
-}
-
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
@@ -42,8 +37,8 @@ impl fmt::Display for Value {
                 let mut buff = String::from("[");
                 for a in v.iter() { buff.push_str(&format!("{a} ")) }
                 if buff.ends_with(" ") { buff.pop(); }
-                buff.push_str(&format!("]"));
-                write!(f, "{}", buff.to_string())
+                buff.push(']');
+                write!(f, "{}", buff)
             },
             Value::Char(v) => write!(f, "{}", v),
             Value::Ptr(v) => write!(f, "Ptr({})", v),
@@ -96,10 +91,10 @@ pub fn stdin_read() -> Result<Vec<u8>, String> {
     let mut buffer = vec![];
     let result = io::stdin().read(&mut buffer);
     if result.is_err() {
-        return Err(format!("IO Error"));
+        return Err("IO Error".to_string());
     }
 
-    return Ok(buffer);
+    Ok(buffer)
 }
 
 // Read a line as string:
@@ -107,27 +102,27 @@ pub fn read_line() -> Result<String, String> {
     let mut string_buffer = String::new();
     let result = io::stdin().read_line(&mut string_buffer);
     if result.is_err() {
-        return Err(format!("IO Error"));
+        return Err("IO Error".to_string());
     }
 
     string_buffer = string_buffer.replace("\n", "");
-    return Ok(string_buffer);
+    Ok(string_buffer)
 }
 
 // Write stdout output:
-pub fn stdout_write(data: &Vec<u8>) -> Result<usize, String> {
+pub fn stdout_write(data: &[u8]) -> Result<usize, String> {
     let stdout = io::stdout();
     let mut lock = stdout.lock();
-    let mut result = lock.write_all(&data);
+    let mut result = lock.write_all(data);
 
     if result.is_err() {
-        return Err(format!("IO Error"));
+        return Err("IO Error".to_string());
     }
 
     result = lock.flush();
     if result.is_err() {
-        return Err(format!("IO Error"));
+        return Err("IO Error".to_string());
     }
 
-    return Ok(data.len());
+    Ok(data.len())
 }
@@ -0,0 +1,102 @@
+//! Time the programs next to this file on the fast loop of `Vm::run` and on
+//! the checked path of `Vm::step`, at each level of `optimize`.
+//!
+//! ```text
+//! cargo bench -p chs_vm_v2 [NAME...]
+//! ```
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use chs_parser::parse_program;
+use chs_vm_v2::{
+    compiler::{compile_with_options, Options},
+    instructions::Bytecode,
+    optimize::{optimize, MAX_LEVEL},
+    trace::Profiler,
+    Vm,
+};
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("gcd", include_str!("gcd.chs")),
+    ("fib", include_str!("fib.chs")),
+    ("rule110", include_str!("rule110.chs")),
+];
+
+/// Runs of each program, of which the fastest is reported.
+const RUNS: usize = 5;
+
+fn compile(name: &str, source: &str, level: u8) -> Bytecode {
+    let mut program = parse_program(source, name).expect("benchmarks parse");
+    type_check::check_program(&mut program).expect("benchmarks type check");
+    let bytecode = compile_with_options(&program, &HashMap::default(), Options::level(level));
+    optimize(&bytecode, level)
+}
+
+/// Instructions the program runs and the code it exits with.
+fn profile(program: &Bytecode) -> (u64, i32) {
+    let profiler = Rc::new(RefCell::new(Profiler::default()));
+    let mut vm = Vm::new(program.clone());
+    vm.set_tracer(profiler.clone());
+    let status = vm.run().expect("benchmarks do not trap");
+    let total = profiler.borrow().total;
+    (total, status.code)
+}
+
+/// The fastest of `RUNS` runs of `program`, with `run` taking it to its end.
+fn time(program: &Bytecode, code: i32, run: impl Fn(&mut Vm) -> i32) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut vm = Vm::new(program.clone());
+            let start = Instant::now();
+            let exit = run(&mut vm);
+            let elapsed = start.elapsed();
+            assert_eq!(exit, code, "the fast loop and the checked path agree");
+            elapsed
+        })
+        .min()
+        .expect("RUNS is not 0")
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`.
+    let names: Vec<String> = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    println!(
+        "{:<10} {:>3} {:>12} {:>12} {:>12} {:>8} {:>10}",
+        "program", "-O", "instructions", "checked", "fast", "speedup", "Minstr/s"
+    );
+    for (name, source) in PROGRAMS {
+        if !names.is_empty() && !names.iter().any(|n| n == name) {
+            continue;
+        }
+        for level in [0, MAX_LEVEL] {
+            let program = compile(name, source, level);
+            let (instructions, code) = profile(&program);
+            let checked = time(&program, code, |vm| loop {
+                if let Some(status) = vm.step().expect("benchmarks do not trap") {
+                    break status.code;
+                }
+            });
+            let fast = time(&program, code, |vm| {
+                vm.run().expect("benchmarks do not trap").code
+            });
+            println!(
+                "{:<10} {:>3} {:>12} {:>10.2}ms {:>10.2}ms {:>7.2}x {:>10.1}",
+                name,
+                level,
+                instructions,
+                checked.as_secs_f64() * 1e3,
+                fast.as_secs_f64() * 1e3,
+                checked.as_secs_f64() / fast.as_secs_f64(),
+                instructions as f64 / fast.as_secs_f64() / 1e6,
+            );
+        }
+    }
+}
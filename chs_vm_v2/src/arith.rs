@@ -0,0 +1,138 @@
+//! What the instructions that compute a value from others do, for the
+//! interpreter, its fast loop and the constant folding of the optimizer.
+//!
+//! Operands are in the order they were pushed. The ones that divide are not
+//! called with a divisor of zero, which stops the program instead.
+use crate::{instructions::Instr, Value};
+
+pub fn add(a: Value, b: Value) -> Value {
+    a.wrapping_add(b)
+}
+pub fn sub(a: Value, b: Value) -> Value {
+    a.wrapping_sub(b)
+}
+pub fn mul(a: Value, b: Value) -> Value {
+    a.wrapping_mul(b)
+}
+pub fn div(a: Value, b: Value) -> Value {
+    a / b
+}
+pub fn div_s(a: Value, b: Value) -> Value {
+    (a as i64).wrapping_div(b as i64) as u64
+}
+pub fn rem(a: Value, b: Value) -> Value {
+    a % b
+}
+pub fn rem_s(a: Value, b: Value) -> Value {
+    (a as i64).wrapping_rem(b as i64) as u64
+}
+pub fn eq(a: Value, b: Value) -> Value {
+    (a == b) as u64
+}
+pub fn ne(a: Value, b: Value) -> Value {
+    (a != b) as u64
+}
+pub fn lt(a: Value, b: Value) -> Value {
+    (a < b) as u64
+}
+pub fn gt(a: Value, b: Value) -> Value {
+    (a > b) as u64
+}
+pub fn le(a: Value, b: Value) -> Value {
+    (a <= b) as u64
+}
+pub fn ge(a: Value, b: Value) -> Value {
+    (a >= b) as u64
+}
+pub fn lt_s(a: Value, b: Value) -> Value {
+    ((a as i64) < b as i64) as u64
+}
+pub fn gt_s(a: Value, b: Value) -> Value {
+    (a as i64 > b as i64) as u64
+}
+pub fn le_s(a: Value, b: Value) -> Value {
+    (a as i64 <= b as i64) as u64
+}
+pub fn ge_s(a: Value, b: Value) -> Value {
+    (a as i64 >= b as i64) as u64
+}
+pub fn and(a: Value, b: Value) -> Value {
+    a & b
+}
+pub fn or(a: Value, b: Value) -> Value {
+    a | b
+}
+pub fn xor(a: Value, b: Value) -> Value {
+    a ^ b
+}
+pub fn shl(a: Value, b: Value) -> Value {
+    u32::try_from(b)
+        .ok()
+        .and_then(|b| a.checked_shl(b))
+        .unwrap_or(0)
+}
+pub fn shr(a: Value, b: Value) -> Value {
+    u32::try_from(b)
+        .ok()
+        .and_then(|b| a.checked_shr(b))
+        .unwrap_or(0)
+}
+pub fn sar(a: Value, b: Value) -> Value {
+    let a = a as i64;
+    let fill = if a < 0 { -1 } else { 0 };
+    let shifted = u32::try_from(b).ok().and_then(|b| a.checked_shr(b));
+    shifted.unwrap_or(fill) as u64
+}
+pub fn not(a: Value) -> Value {
+    (a == 0) as u64
+}
+pub fn trunc(a: Value, bits: u32) -> Value {
+    a & (u64::MAX >> (64 - bits))
+}
+pub fn sign_ext(a: Value, bits: u32) -> Value {
+    let shift = 64 - bits;
+    (((a << shift) as i64) >> shift) as u64
+}
+
+/// What an instruction that takes two values and leaves one computes.
+pub fn binary(instr: &Instr) -> Option<fn(Value, Value) -> Value> {
+    let op = match instr {
+        Instr::PlusI | Instr::Offset => add,
+        Instr::MinusI => sub,
+        Instr::MultI => mul,
+        Instr::DivI => div,
+        Instr::DivS => div_s,
+        Instr::Mod => rem,
+        Instr::ModS => rem_s,
+        Instr::EqI => eq,
+        Instr::NEqI => ne,
+        Instr::Lt => lt,
+        Instr::Gt => gt,
+        Instr::Le => le,
+        Instr::Ge => ge,
+        Instr::LtS => lt_s,
+        Instr::GtS => gt_s,
+        Instr::LeS => le_s,
+        Instr::GeS => ge_s,
+        Instr::BitAnd => and,
+        Instr::BitOr => or,
+        Instr::BitXor => xor,
+        Instr::Shl => shl,
+        Instr::Shr => shr,
+        Instr::Sar => sar,
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// What an instruction that takes one value and leaves one makes of `a`.
+pub fn unary(instr: &Instr, a: Value) -> Option<Value> {
+    let value = match *instr {
+        Instr::BitNot => !a,
+        Instr::Not => not(a),
+        Instr::Trunc(bits) => trunc(a, bits),
+        Instr::SignExt(bits) => sign_ext(a, bits),
+        _ => return None,
+    };
+    Some(value)
+}
@@ -1,148 +1,359 @@
 use std::{collections::HashMap, rc::Rc};
 
-use chs_parser::Operation;
+use chs_parser::{visit::walk_op, DataType, Loc, Operation, OperationKind, Signature, Visitor};
 
-use crate::instructions::{Bytecode, Instr};
+use crate::{
+    instructions::{Bytecode, Export, Instr},
+    jump_to,
+};
+
+/// Instructions a fn can have to be inlined at `Options::level` 2.
+pub const INLINE_LIMIT: usize = 8;
+
+/// What the compiler does besides compiling each operation as it is written.
+/// A fn marked `noinline` is never inlined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Compile a call that is the last thing a fn does as a jump, so that
+    /// recursion in tail position does not grow the return stack. Only calls
+    /// to fns that return as many values as the caller are jumps.
+    pub tail_calls: bool,
+    /// Compile calls to fns of at most this many instructions that do not
+    /// call themselves as the instructions of the fn. 0 inlines nothing. Fns
+    /// with strings are not inlined, as each copy would add the string to
+    /// memory again.
+    pub inline: usize,
+}
+
+impl Options {
+    /// The options of `chsi -O`: tail calls from level 1 and inlining from
+    /// level 2.
+    pub fn level(level: u8) -> Self {
+        Self {
+            tail_calls: level >= 1,
+            inline: if level >= 2 { INLINE_LIMIT } else { 0 },
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 struct CompCtx {
     instr: Vec<Instr>,
+    /// Source location of each instruction.
+    locs: Vec<Loc>,
+    /// Location of the operation being compiled.
+    loc: Loc,
     strs: Vec<Rc<[u8]>>,
+    strs_size: usize,
+    /// `PushPtr`s of allocated memory, which starts after the strings.
+    mem_refs: Vec<usize>,
     fn_def: HashMap<String, usize>,
+    exports: HashMap<String, Export>,
+    /// Host functions that can be called, and the ones that are.
+    native_defs: HashMap<String, Signature>,
+    natives: Vec<String>,
     mem_def: HashMap<String, usize>,
     mem_size: usize,
+    /// Slot of each bind in the frame of the fn being compiled.
     binds: HashMap<String, usize>,
+    /// Binds live at this point of the fn being compiled.
+    frame_binds: usize,
+    options: Options,
+    /// The operation being compiled is the last of its fn, so only the
+    /// `Ret` runs after it.
+    tail: bool,
+    /// Values returned by the fn being compiled.
+    outs: usize,
+    /// Bodies of the fns that calls are compiled as.
+    inline: HashMap<String, Rc<[Operation]>>,
 }
 
-pub fn compile(ops: Vec<Operation>) -> Bytecode {
-    let mut ctx = CompCtx::default();
-    let mut ops_iter = ops.into_iter();
-    while let Some(expr) = ops_iter.next() {
-        compile_op(&mut ctx, expr);
+impl CompCtx {
+    fn emit(&mut self, instr: Instr) {
+        self.instr.push(instr);
+        self.locs.push(self.loc);
+    }
+}
+
+impl Visitor for CompCtx {
+    fn visit_op(&mut self, op: &Operation) {
+        let outer = std::mem::replace(&mut self.loc, op.loc);
+        compile_op(self, op);
+        self.loc = outer;
+    }
+}
+
+pub fn compile(ops: &[Operation]) -> Bytecode {
+    compile_with(ops, &HashMap::default())
+}
+
+/// Like `compile`, calling the host functions in `natives` for the words that
+/// name them. These are the signatures the program was type checked with.
+pub fn compile_with(ops: &[Operation], natives: &HashMap<String, Signature>) -> Bytecode {
+    compile_with_options(ops, natives, Options::default())
+}
+
+/// Like `compile_with`, with tail calls and inlining as set by `options`.
+pub fn compile_with_options(
+    ops: &[Operation],
+    natives: &HashMap<String, Signature>,
+    options: Options,
+) -> Bytecode {
+    let mut ctx = CompCtx {
+        native_defs: natives.clone(),
+        options,
+        ..Default::default()
+    };
+    ctx.visit_ops(ops);
+    for &i in &ctx.mem_refs {
+        if let Instr::PushPtr(addr) = &mut ctx.instr[i] {
+            *addr += ctx.strs_size;
+        }
     }
     Bytecode {
         program: ctx.instr,
+        locs: ctx.locs,
         program_mem: ctx.mem_size,
         entry: 0,
         strs: ctx.strs,
+        exports: ctx.exports,
+        natives: ctx.natives,
+    }
+}
+
+/// Compile `ops`, the last of them in tail position when `tail` is.
+fn visit_body(ctx: &mut CompCtx, ops: &[Operation], tail: bool) {
+    if let Some((last, ops)) = ops.split_last() {
+        ctx.visit_ops(ops);
+        ctx.tail = tail;
+        ctx.visit_op(last);
     }
 }
 
-fn compile_op(ctx: &mut CompCtx, op: Operation) {
-    match op {
-        Operation::PushI(i) => ctx.instr.push(Instr::PushI32(i)),
-        Operation::Sys(i) => ctx.instr.push(Instr::Sys(i)),
-        Operation::Str(s) => {
-            ctx.instr.push(Instr::PushI32(s.len() as i32));
-            ctx.instr.push(Instr::PushPtr(ctx.strs.len()));
-            ctx.strs.push(s.into_bytes().into());
+fn compile_op(ctx: &mut CompCtx, op: &Operation) {
+    let tail = std::mem::take(&mut ctx.tail);
+    match &op.kind {
+        OperationKind::PushI(i) => ctx.emit(Instr::PushI32(*i)),
+        OperationKind::PushBool(b) => ctx.emit(Instr::PushI32(*b as i32)),
+        OperationKind::Sys(call) => ctx.emit(Instr::Sys(*call)),
+        OperationKind::Str(s) => {
+            ctx.emit(Instr::PushI32(s.len() as i32));
+            ctx.emit(Instr::PushPtr(ctx.strs_size));
+            ctx.strs_size += s.len();
+            ctx.strs.push(s.as_bytes().into());
         }
-        Operation::Debug => ctx.instr.push(Instr::Debug),
-        Operation::Alloc(name, size) => {
-            ctx.mem_def.insert(name, ctx.mem_size);
+        OperationKind::Debug => ctx.emit(Instr::Debug),
+        OperationKind::Const(..) => {}
+        OperationKind::Alloc(name, size, _) => {
+            ctx.mem_def.insert(name.clone(), ctx.mem_size);
             ctx.mem_size += size;
         }
-        Operation::Let(names, body) => {
-            ctx.instr.push(Instr::LetBind(names.len()));
+        OperationKind::Let(names, body) => {
+            ctx.emit(Instr::LetBind(names.len()));
+            let outer = ctx.binds.clone();
             for (i, name) in names.iter().rev().enumerate() {
-                ctx.binds.insert(name.clone(), i);
+                ctx.binds.insert(name.clone(), ctx.frame_binds + i);
             }
-            for op in body.to_vec() {
-                compile_op(ctx, op)
-            }
-            for name in names.iter().rev() {
-                ctx.binds.remove(name);
-            }
-            ctx.instr.push(Instr::UnBind(names.len()));
+            ctx.frame_binds += names.len();
+            visit_body(ctx, body, tail);
+            ctx.frame_binds -= names.len();
+            ctx.binds = outer;
+            ctx.emit(Instr::UnBind(names.len()));
         }
-        Operation::If(body) => {
+        OperationKind::If(body) => {
             let offset = ctx.instr.len();
-            ctx.instr.push(Instr::JmpIf(0));
-            for op in body.to_vec() {
-                compile_op(ctx, op)
-            }
+            ctx.emit(Instr::JmpIf(0));
+            visit_body(ctx, body, tail);
             let curr_len = ctx.instr.len();
             let elem = unsafe { ctx.instr.get_unchecked_mut(offset) };
             *elem = Instr::JmpIf((curr_len - offset) as isize);
         }
-        Operation::IfElse(ifbody, elsebody) => {
+        OperationKind::IfElse(ifbody, elsebody) => {
             let place_horder = ctx.instr.len();
-            ctx.instr.push(Instr::Halt); // Placeholder
-            for op in ifbody.to_vec() {
-                compile_op(ctx, op)
-            }
+            ctx.emit(Instr::Halt); // Placeholder
+            visit_body(ctx, ifbody, tail);
             let offset2 = ctx.instr.len();
-            ctx.instr.push(Instr::Jmp(0));
+            ctx.emit(Instr::Jmp(0));
             let elem = unsafe { ctx.instr.get_unchecked_mut(place_horder) };
             *elem = Instr::JmpIf((offset2 - (place_horder) + 1) as isize);
-            for op in elsebody.to_vec() {
-                compile_op(ctx, op)
-            }
+            visit_body(ctx, elsebody, tail);
             let curr_len = ctx.instr.len();
             let elem = unsafe { ctx.instr.get_unchecked_mut(offset2) };
             *elem = Instr::Jmp((curr_len - offset2) as isize);
         }
-        Operation::While(cond, body) => {
+        OperationKind::While(cond, body) => {
             let whileaddrs = ctx.instr.len();
-            for op in cond.to_vec() {
-                compile_op(ctx, op)
-            }
+            ctx.visit_ops(cond);
             let ifaddrs = ctx.instr.len();
-            ctx.instr.push(Instr::JmpIf(0));
-            for op in body.to_vec() {
-                compile_op(ctx, op)
-            }
+            ctx.emit(Instr::JmpIf(0));
+            ctx.visit_ops(body);
             let curr_len = ctx.instr.len();
-            ctx.instr
-                .push(Instr::Jmp(-((curr_len - whileaddrs) as isize)));
+            ctx.emit(Instr::Jmp(-((curr_len - whileaddrs) as isize)));
             let curr_len = ctx.instr.len();
             let elem = unsafe { ctx.instr.get_unchecked_mut(ifaddrs) };
             *elem = Instr::JmpIf((curr_len - ifaddrs) as isize);
         }
-        Operation::Bind(n) => ctx.instr.push(Instr::Bind(n)),
-        Operation::Intrinsic(a) if a.as_str() == "+" => ctx.instr.push(Instr::PlusI),
-        Operation::Intrinsic(a) if a.as_str() == "*" => ctx.instr.push(Instr::MultI),
-        Operation::Intrinsic(a) if a.as_str() == "mod" => ctx.instr.push(Instr::Mod),
-        Operation::Intrinsic(a) if a.as_str() == "==" => ctx.instr.push(Instr::EqI),
-        Operation::Intrinsic(a) if a.as_str() == "!=" => ctx.instr.push(Instr::NEqI),
-        Operation::Intrinsic(a) if a.as_str() == "<" => ctx.instr.push(Instr::Lt),
-        Operation::Intrinsic(a) if a.as_str() == "drop" => ctx.instr.push(Instr::Drop),
-        Operation::Intrinsic(a) if a.as_str() == "dup" => ctx.instr.push(Instr::Dup),
-        Operation::Intrinsic(a) if a.as_str() == "over" => ctx.instr.push(Instr::Over),
-        Operation::Intrinsic(a) if a.as_str() == "rot" => ctx.instr.push(Instr::Rot),
-        Operation::Intrinsic(a) if a.as_str() == "swap" => ctx.instr.push(Instr::Swap),
-        Operation::Intrinsic(a) if a.as_str() == "offset" => ctx.instr.push(Instr::Offset),
-        Operation::Write(a) => ctx.instr.push(Instr::Write(a)),
-        Operation::Read(a) => ctx.instr.push(Instr::Read(a)),
-        Operation::Fn(name, _args, _, _, body) => {
+        OperationKind::Bind(n) => ctx.emit(Instr::Bind(*n)),
+        OperationKind::Intrinsic(a, typ) => compile_intrinsic(ctx, a, typ.unwrap_or(DataType::Int)),
+        OperationKind::Cast(typ) => wrap(ctx, *typ),
+        OperationKind::Write(a) => ctx.emit(Instr::Write(*a)),
+        OperationKind::Read(a) => ctx.emit(Instr::Read(*a)),
+        OperationKind::Fn(name, _args, ins, outs, body, _, noinline) => {
             let addrs = ctx.instr.len();
-            ctx.instr.push(Instr::Jmp(0));
+            ctx.emit(Instr::Jmp(0));
             let curr_len = ctx.instr.len();
-            ctx.fn_def.insert(name, curr_len);
-            for op in body.to_vec() {
-                compile_op(ctx, op)
+            ctx.fn_def.insert(name.clone(), curr_len);
+            let signature = Signature {
+                ins: ins.clone(),
+                outs: outs.clone(),
+            };
+            ctx.exports.insert(
+                name.clone(),
+                Export {
+                    addr: curr_len,
+                    signature,
+                },
+            );
+            // A call has its own frame, so the binds around the fn are not
+            // in scope.
+            let outer = std::mem::take(&mut ctx.binds);
+            let outer_frame = std::mem::take(&mut ctx.frame_binds);
+            let outer_outs = std::mem::replace(&mut ctx.outs, outs.len());
+            visit_body(ctx, body, ctx.options.tail_calls);
+            ctx.binds = outer;
+            ctx.frame_binds = outer_frame;
+            ctx.outs = outer_outs;
+            let len = ctx.instr.len() - curr_len;
+            ctx.emit(Instr::Ret);
+            if !*noinline && len <= ctx.options.inline && inlinable(body, name) {
+                ctx.inline.insert(name.clone(), body.clone());
             }
-            ctx.instr.push(Instr::Ret);
             let curr_len = ctx.instr.len();
             let elem = unsafe { ctx.instr.get_unchecked_mut(addrs) };
             *elem = Instr::Jmp((curr_len - addrs) as isize);
         }
-        Operation::Word(name) => {
-            if let Some(fnn) = ctx.fn_def.get(&name) {
-                ctx.instr.push(Instr::Call(*fnn));
-            } else if let Some(mem) = ctx.mem_def.get(&name) {
-                ctx.instr.push(Instr::PushPtr(*mem));
-            } else if let Some(bind) = ctx.binds.get(&name) {
-                ctx.instr.push(Instr::PushBind(*bind))
+        OperationKind::Word(name) => {
+            if let Some(&addr) = ctx.fn_def.get(name) {
+                compile_call(ctx, name, addr, tail);
+            } else if ctx.native_defs.contains_key(name) {
+                let index = match ctx.natives.iter().position(|n| n == name) {
+                    Some(index) => index,
+                    None => {
+                        ctx.natives.push(name.clone());
+                        ctx.natives.len() - 1
+                    }
+                };
+                ctx.emit(Instr::Native(index));
+            } else if let Some(mem) = ctx.mem_def.get(name) {
+                ctx.mem_refs.push(ctx.instr.len());
+                ctx.emit(Instr::PushPtr(*mem));
+            } else if let Some(bind) = ctx.binds.get(name) {
+                ctx.emit(Instr::PushBind(*bind))
             }
         }
-        e => {
-            dbg!(e);
-            todo!()
+        OperationKind::Assing(..) => unreachable!("`type_check` rejects assignments"),
+    }
+}
+
+/// A call to the fn `name` at `addr`, inlined or as a jump when it can be.
+fn compile_call(ctx: &mut CompCtx, name: &str, addr: usize, tail: bool) {
+    if let Some(body) = ctx.inline.get(name).cloned() {
+        // The binds of the caller are not in scope in the fn, but its own
+        // go after them in the frame.
+        let outer = std::mem::take(&mut ctx.binds);
+        visit_body(ctx, &body, tail);
+        ctx.binds = outer;
+        return;
+    }
+    let outs = ctx.exports[name].signature.outs.len();
+    if tail && outs == ctx.outs {
+        // The fn returns where the caller would have, and its binds start
+        // where the ones of the caller did.
+        if ctx.frame_binds > 0 {
+            ctx.emit(Instr::UnBind(ctx.frame_binds));
         }
+        ctx.emit(Instr::Jmp(jump_to(ctx.instr.len(), addr)));
+    } else {
+        ctx.emit(Instr::Call(addr));
     }
 }
 
-/*
+/// Whether `body`, of the fn `name`, can be compiled in place of its calls:
+/// it does not call itself and has no strings.
+fn inlinable(body: &[Operation], name: &str) -> bool {
+    struct Inlinable<'a> {
+        name: &'a str,
+        found: bool,
+    }
+    impl Visitor for Inlinable<'_> {
+        fn visit_op(&mut self, op: &Operation) {
+            match &op.kind {
+                OperationKind::Word(word) if word == self.name => self.found = true,
+                OperationKind::Str(_) => self.found = true,
+                _ => {}
+            }
+            walk_op(self, op);
+        }
+    }
+    let mut v = Inlinable { name, found: false };
+    v.visit_ops(body);
+    !v.found
+}
 
-*/
+/// Intrinsics on operands of type `typ`, which the type checker records and
+/// which is `int` for programs that were not checked. Results of arithmetic on
+/// types narrower than 64 bits are wrapped back to their width.
+fn compile_intrinsic(ctx: &mut CompCtx, name: &str, typ: DataType) {
+    let signed = typ.is_signed();
+    let instr = match name {
+        "+" => Instr::PlusI,
+        "-" => Instr::MinusI,
+        "*" => Instr::MultI,
+        "/" if signed => Instr::DivS,
+        "/" => Instr::DivI,
+        "mod" if signed => Instr::ModS,
+        "mod" => Instr::Mod,
+        "==" => Instr::EqI,
+        "!=" => Instr::NEqI,
+        "<" if signed => Instr::LtS,
+        "<" => Instr::Lt,
+        ">" if signed => Instr::GtS,
+        ">" => Instr::Gt,
+        "<=" if signed => Instr::LeS,
+        "<=" => Instr::Le,
+        ">=" if signed => Instr::GeS,
+        ">=" => Instr::Ge,
+        "&" => Instr::BitAnd,
+        "|" => Instr::BitOr,
+        "^" => Instr::BitXor,
+        "~" => Instr::BitNot,
+        "<<" => Instr::Shl,
+        ">>" if signed => Instr::Sar,
+        ">>" => Instr::Shr,
+        // Booleans are 0 or 1, so the bitwise instructions work for them.
+        "and" => Instr::BitAnd,
+        "or" => Instr::BitOr,
+        "not" => Instr::Not,
+        "drop" => Instr::Drop,
+        "dup" => Instr::Dup,
+        "over" => Instr::Over,
+        "rot" => Instr::Rot,
+        "swap" => Instr::Swap,
+        "offset" => Instr::Offset,
+        // `type_check` rejects the words that are not intrinsics.
+        _ => unreachable!("unknown intrinsic {}", name),
+    };
+    ctx.emit(instr);
+    if matches!(name, "+" | "-" | "*" | "/" | "~" | "<<") {
+        wrap(ctx, typ);
+    }
+}
+
+/// Bring a 64-bit result back to the width of `typ`.
+fn wrap(ctx: &mut CompCtx, typ: DataType) {
+    match typ.bits() {
+        Some(64) | None => {}
+        Some(bits) if typ.is_signed() => ctx.emit(Instr::SignExt(bits)),
+        Some(bits) => ctx.emit(Instr::Trunc(bits)),
+    }
+}
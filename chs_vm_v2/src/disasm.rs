@@ -0,0 +1,119 @@
+//! Text listing of `Bytecode`, for looking at what the compiler made of a
+//! program.
+//!
+//! ```
+//! use chs_parser::parse_program;
+//! use chs_vm_v2::{compiler::compile, disasm::disassemble};
+//!
+//! let program = parse_program("fn inc : int -> int { 1 + } 2 inc drop", "inc.chs").unwrap();
+//! let listing = disassemble(&compile(&program));
+//! assert!(listing.contains("inc:\n"));
+//! assert!(listing.contains("Call 1 <inc>"));
+//! ```
+use std::fmt::Write;
+
+use crate::{
+    instructions::{Bytecode, Instr},
+    jump,
+    verify::name,
+};
+
+/// List the string table, the host functions and the instructions of
+/// `program`, one per line with its address. Fns are labeled by their name,
+/// jumps and calls show where they go and pointers to strings show the string.
+pub fn disassemble(program: &Bytecode) -> String {
+    let mut out = String::new();
+    let mut addr = 0;
+    if !program.strs.is_empty() {
+        out.push_str("strings:\n");
+    }
+    for (i, s) in program.strs.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "  {:>4}  @{} {:?}",
+            i,
+            addr,
+            String::from_utf8_lossy(s)
+        );
+        addr += s.len();
+    }
+    if !program.natives.is_empty() {
+        out.push_str("natives:\n");
+    }
+    for (i, name) in program.natives.iter().enumerate() {
+        let _ = writeln!(out, "  {:>4}  {}", i, name);
+    }
+    let _ = writeln!(out, "entry: {}", program.entry);
+    for ip in 0..program.len() {
+        if let Some(name) = fn_at(program, ip) {
+            let _ = writeln!(out, "{}:", name);
+        }
+        let text = instruction(program, ip);
+        let _ = match program.loc(ip) {
+            Some(loc) => writeln!(
+                out,
+                "  {:>4}  {:<28}; {}:{}",
+                ip,
+                text,
+                loc.line(),
+                loc.col()
+            ),
+            None => writeln!(out, "  {:>4}  {}", ip, text),
+        };
+    }
+    out
+}
+
+/// The fn that starts at `addr`, if any.
+pub fn fn_at(program: &Bytecode, addr: usize) -> Option<&str> {
+    program
+        .exports
+        .iter()
+        .filter(|(_, export)| export.addr == addr)
+        .map(|(name, _)| name.as_str())
+        .min()
+}
+
+/// The instruction at `ip` with its operand, like in `disassemble`.
+pub fn instruction(program: &Bytecode, ip: usize) -> String {
+    let instr = &program.program[ip];
+    let mut text = name(instr);
+    let _ = match *instr {
+        Instr::Jmp(rel) | Instr::JmpIf(rel) => write!(text, " {:+} -> {}", rel, jump(ip, rel)),
+        Instr::AJmp(addr) | Instr::AJmpIf(addr) => write!(text, " {}", addr),
+        Instr::Call(addr) => match fn_at(program, addr) {
+            Some(name) => write!(text, " {} <{}>", addr, name),
+            None => write!(text, " {}", addr),
+        },
+        Instr::Native(index) => match program.natives.get(index) {
+            Some(name) => write!(text, " {} <{}>", index, name),
+            None => write!(text, " {}", index),
+        },
+        Instr::PushPtr(ptr) => match str_at(program, ptr) {
+            Some(s) => write!(text, " {} {:?}", ptr, s),
+            None => write!(text, " {}", ptr),
+        },
+        Instr::Sys(call) => write!(text, " {}", call),
+        Instr::LetBind(n)
+        | Instr::PushBind(n)
+        | Instr::UnBind(n)
+        | Instr::Write(n)
+        | Instr::Read(n) => write!(text, " {}", n),
+        Instr::Bind(n) | Instr::Trunc(n) | Instr::SignExt(n) => write!(text, " {}", n),
+        Instr::PushI32(v) => write!(text, " {}", v),
+        _ => Ok(()),
+    };
+    text
+}
+
+/// The string of the string table that starts at `ptr`.
+fn str_at(program: &Bytecode, ptr: usize) -> Option<String> {
+    let mut addr = 0;
+    for s in &program.strs {
+        if addr == ptr {
+            return Some(String::from_utf8_lossy(s).into_owned());
+        }
+        addr += s.len();
+    }
+    None
+}
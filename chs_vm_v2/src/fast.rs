@@ -0,0 +1,408 @@
+//! The loop `Vm::run` and `Vm::call` use for programs that pass `verify`.
+//!
+//! The program is first lowered to `Op`s of 8 bytes, with jumps to absolute
+//! addresses and syscalls by number. The loop then keeps the top of the data
+//! stack in a local and reads the others from memory without checks: the
+//! verifier has made sure no instruction takes more values than there are.
+//! Pushing still checks for overflow, and memory accesses, division and the
+//! return stack are checked like `Machine::step` does. Instructions that are
+//! rare or that look at the whole stack, like `Sys` and `Debug`, are left to
+//! `Machine::step`.
+use std::collections::HashMap;
+
+use chs_parser::Signature;
+
+use crate::{
+    arith,
+    instructions::{Bytecode, Instr},
+    jump,
+    verify::verify_with,
+    Machine, TrapKind, Value,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Code {
+    Halt,
+    Drop,
+    Dup,
+    Rot,
+    Over,
+    Swap,
+    PlusI,
+    MinusI,
+    MultI,
+    DivI,
+    DivS,
+    Mod,
+    ModS,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    LtS,
+    GtS,
+    LeS,
+    GeS,
+    EqI,
+    NEqI,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    Sar,
+    Not,
+    Ret,
+    LetBind,
+    PushBind,
+    UnBind,
+    Write,
+    Read,
+    Call,
+    Bind,
+    Trunc,
+    SignExt,
+    PushI32,
+    PushPtr,
+    Jmp,
+    JmpIf,
+    /// Run by `Machine::step`.
+    Step,
+}
+
+/// An instruction with its operand.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Op {
+    code: Code,
+    arg: u32,
+}
+
+const _: () = assert!(size_of::<Op>() == 8);
+
+/// `program` as `Op`s, when it passes `verify_with` the signatures of
+/// `natives` and its operands fit in 32 bits.
+pub(crate) fn lower(program: &Bytecode, natives: &HashMap<String, Signature>) -> Option<Vec<Op>> {
+    verify_with(program, natives).ok()?;
+    // `verify` follows `main` from the values it takes, but it is run on
+    // what the top-level code left.
+    if program
+        .exports
+        .get("main")
+        .is_some_and(|main| !main.signature.ins.is_empty())
+    {
+        return None;
+    }
+    let op = |code, arg: usize| {
+        Some(Op {
+            code,
+            arg: u32::try_from(arg).ok()?,
+        })
+    };
+    let simple = |code| Some(Op { code, arg: 0 });
+    let ops = program
+        .program
+        .iter()
+        .enumerate()
+        .map(|(ip, instr)| match *instr {
+            Instr::Halt => simple(Code::Halt),
+            Instr::Drop => simple(Code::Drop),
+            Instr::Dup => simple(Code::Dup),
+            Instr::Rot => simple(Code::Rot),
+            Instr::Over => simple(Code::Over),
+            Instr::Swap => simple(Code::Swap),
+            Instr::PlusI | Instr::Offset => simple(Code::PlusI),
+            Instr::MinusI => simple(Code::MinusI),
+            Instr::MultI => simple(Code::MultI),
+            Instr::DivI => simple(Code::DivI),
+            Instr::DivS => simple(Code::DivS),
+            Instr::Mod => simple(Code::Mod),
+            Instr::ModS => simple(Code::ModS),
+            Instr::Lt => simple(Code::Lt),
+            Instr::Gt => simple(Code::Gt),
+            Instr::Le => simple(Code::Le),
+            Instr::Ge => simple(Code::Ge),
+            Instr::LtS => simple(Code::LtS),
+            Instr::GtS => simple(Code::GtS),
+            Instr::LeS => simple(Code::LeS),
+            Instr::GeS => simple(Code::GeS),
+            Instr::EqI => simple(Code::EqI),
+            Instr::NEqI => simple(Code::NEqI),
+            Instr::BitAnd => simple(Code::BitAnd),
+            Instr::BitOr => simple(Code::BitOr),
+            Instr::BitXor => simple(Code::BitXor),
+            Instr::BitNot => simple(Code::BitNot),
+            Instr::Shl => simple(Code::Shl),
+            Instr::Shr => simple(Code::Shr),
+            Instr::Sar => simple(Code::Sar),
+            Instr::Not => simple(Code::Not),
+            Instr::Ret => simple(Code::Ret),
+            Instr::LetBind(n) => op(Code::LetBind, n),
+            Instr::PushBind(slot) => op(Code::PushBind, slot),
+            Instr::UnBind(n) => op(Code::UnBind, n),
+            Instr::Write(bytes) => op(Code::Write, bytes),
+            Instr::Read(bytes) => op(Code::Read, bytes),
+            Instr::Call(addr) => op(Code::Call, addr),
+            Instr::Bind(rel) => op(Code::Bind, rel as usize),
+            Instr::Trunc(bits) => op(Code::Trunc, bits as usize),
+            Instr::SignExt(bits) => op(Code::SignExt, bits as usize),
+            Instr::PushI32(v) => simple(Code::PushI32).map(|op| Op {
+                arg: v as u32,
+                ..op
+            }),
+            Instr::PushPtr(ptr) => op(Code::PushPtr, ptr),
+            Instr::Jmp(rel) => op(Code::Jmp, jump(ip, rel)),
+            Instr::JmpIf(rel) => op(Code::JmpIf, jump(ip, rel)),
+            Instr::AJmp(addr) => op(Code::Jmp, addr),
+            Instr::AJmpIf(addr) => op(Code::JmpIf, addr),
+            Instr::Sys(_) | Instr::Native(_) | Instr::Debug => simple(Code::Step),
+        });
+    ops.collect()
+}
+
+impl Machine {
+    /// Run `code`, the lowered `program`, from `ip` to its end. Fuel is only
+    /// taken when `fuel` is limited. On a trap, returns the instruction that
+    /// failed, with the machine as `Machine::step` would have left it.
+    pub(crate) fn run(
+        &mut self,
+        program: &Bytecode,
+        code: &[Op],
+        mut ip: usize,
+        fuel: &mut Option<u64>,
+    ) -> Result<usize, (TrapKind, usize)> {
+        let mut left = fuel.unwrap_or(u64::MAX);
+        let limited = fuel.is_some();
+        let base = self.stack.data.inner;
+        // The stack is the values from `sp` to the end of its memory and
+        // `tos`, the last of them being the spill slot that is not part of
+        // it. So `sp` is one value above the `top` of `VMStack`.
+        let mut sp = self.stack.top + size_of::<Value>();
+        // SAFETY: `top` is in the memory of the stack, at worst on the spill
+        // slot when the stack is empty.
+        let mut tos = unsafe { load(base, self.stack.top) };
+
+        // SAFETY of `pop!`: `verify` has checked that no instruction takes
+        // more values than the fn it is in was given or has pushed, so
+        // there is always a value at `sp`.
+        macro_rules! pop {
+            () => {{
+                let value = tos;
+                tos = unsafe { load(base, sp) };
+                sp += size_of::<Value>();
+                value
+            }};
+        }
+        macro_rules! save {
+            () => {{
+                self.stack.top = sp - size_of::<Value>();
+                unsafe { store(base, self.stack.top, tos) };
+            }};
+        }
+        macro_rules! trap {
+            ($kind:expr) => {{
+                save!();
+                if limited {
+                    *fuel = Some(left);
+                }
+                return Err(($kind, ip));
+            }};
+        }
+        // There is room for another value while `sp` is above the first
+        // value of the memory of the stack, the spill slot being one value
+        // more than it holds.
+        macro_rules! push {
+            ($value:expr) => {{
+                let value = $value;
+                if sp <= size_of::<Value>() {
+                    trap!(TrapKind::StackOverflow);
+                }
+                sp -= size_of::<Value>();
+                unsafe { store(base, sp, tos) };
+                tos = value;
+            }};
+        }
+        macro_rules! binop {
+            ($op:expr) => {{
+                let b = pop!();
+                tos = $op(tos, b);
+            }};
+        }
+        macro_rules! divop {
+            ($op:expr) => {{
+                if tos == 0 {
+                    trap!(TrapKind::DivisionByZero);
+                }
+                binop!($op)
+            }};
+        }
+
+        while let Some(&Op { code: op, arg }) = code.get(ip) {
+            if left == 0 {
+                trap!(TrapKind::OutOfFuel);
+            }
+            if limited {
+                left -= 1;
+            }
+            let mut next = ip + 1;
+            match op {
+                Code::Halt => next = code.len(),
+                Code::Drop => {
+                    pop!();
+                }
+                Code::Dup => push!(tos),
+                Code::Over => push!(unsafe { load(base, sp) }),
+                Code::Swap => unsafe {
+                    let b = tos;
+                    tos = load(base, sp);
+                    store(base, sp, b);
+                },
+                Code::Rot => unsafe {
+                    // a b c -> b c a
+                    let c = tos;
+                    let b = load(base, sp);
+                    let a = load(base, sp + size_of::<Value>());
+                    store(base, sp + size_of::<Value>(), b);
+                    store(base, sp, c);
+                    tos = a;
+                },
+                Code::PlusI => binop!(arith::add),
+                Code::MinusI => binop!(arith::sub),
+                Code::MultI => binop!(arith::mul),
+                Code::DivI => divop!(arith::div),
+                Code::DivS => divop!(arith::div_s),
+                Code::Mod => divop!(arith::rem),
+                Code::ModS => divop!(arith::rem_s),
+                Code::Lt => binop!(arith::lt),
+                Code::Gt => binop!(arith::gt),
+                Code::Le => binop!(arith::le),
+                Code::Ge => binop!(arith::ge),
+                Code::LtS => binop!(arith::lt_s),
+                Code::GtS => binop!(arith::gt_s),
+                Code::LeS => binop!(arith::le_s),
+                Code::GeS => binop!(arith::ge_s),
+                Code::EqI => binop!(arith::eq),
+                Code::NEqI => binop!(arith::ne),
+                Code::BitAnd => binop!(arith::and),
+                Code::BitOr => binop!(arith::or),
+                Code::BitXor => binop!(arith::xor),
+                Code::Shl => binop!(arith::shl),
+                Code::Shr => binop!(arith::shr),
+                Code::Sar => binop!(arith::sar),
+                Code::BitNot => tos = !tos,
+                Code::Not => tos = arith::not(tos),
+                Code::Trunc => tos = arith::trunc(tos, arg),
+                Code::SignExt => tos = arith::sign_ext(tos, arg),
+                Code::PushI32 => push!(arg as i32 as u64),
+                Code::PushPtr => push!(arg as u64),
+                // `Bind 1` is the top.
+                Code::Bind => push!(match arg {
+                    1 => tos,
+                    n => unsafe { load(base, sp + (n as usize - 2) * size_of::<Value>()) },
+                }),
+                Code::Jmp => next = arg as usize,
+                Code::JmpIf => {
+                    if pop!() == 0 {
+                        next = arg as usize;
+                    }
+                }
+                Code::LetBind => {
+                    for _ in 0..arg {
+                        let value = pop!();
+                        if self.locals.push(value).is_none() {
+                            trap!(TrapKind::ReturnStackOverflow);
+                        }
+                    }
+                }
+                Code::PushBind => {
+                    let depth = self.locals.depth();
+                    let value = self.locals.peek(depth - (self.frame + arg as usize));
+                    push!(value.expect("`verify` checks the binds"));
+                }
+                Code::UnBind => {
+                    let depth = self.locals.depth();
+                    self.locals.truncate(depth - arg as usize);
+                }
+                Code::Call => {
+                    if let Err(kind) = self.enter(next) {
+                        trap!(kind);
+                    }
+                    next = arg as usize;
+                }
+                Code::Ret => match self.leave() {
+                    Ok(addr) => next = addr,
+                    Err(kind) => trap!(kind),
+                },
+                Code::Read => {
+                    let addr = pop!();
+                    let value = match arg {
+                        64 => self.read::<u64>(addr),
+                        32 => self.read::<u32>(addr).map(u64::from),
+                        16 => self.read::<u16>(addr).map(u64::from),
+                        8 => self.read::<u8>(addr).map(u64::from),
+                        _ => Err(TrapKind::InvalidWidth(arg as usize)),
+                    };
+                    match value {
+                        Ok(value) => push!(value),
+                        Err(kind) => trap!(kind),
+                    }
+                }
+                Code::Write => {
+                    let addr = pop!();
+                    let value = pop!();
+                    let result = match arg {
+                        64 => self.write(addr, value),
+                        32 => self.write(addr, value as u32),
+                        16 => self.write(addr, value as u16),
+                        8 => self.write(addr, value as u8),
+                        _ => Err(TrapKind::InvalidWidth(arg as usize)),
+                    };
+                    if let Err(kind) = result {
+                        trap!(kind);
+                    }
+                }
+                Code::Step => {
+                    save!();
+                    match self.step(program, ip) {
+                        Ok(addr) => next = addr,
+                        Err(kind) => {
+                            if limited {
+                                *fuel = Some(left);
+                            }
+                            return Err((kind, ip));
+                        }
+                    }
+                    sp = self.stack.top + size_of::<Value>();
+                    tos = unsafe { load(base, self.stack.top) };
+                }
+            }
+            ip = next;
+        }
+        save!();
+        if limited {
+            *fuel = Some(left);
+        }
+        Ok(ip)
+    }
+}
+
+/// Read the value at byte `at` of the memory at `base`.
+///
+/// # Safety
+///
+/// The value must be inside the memory.
+#[inline(always)]
+unsafe fn load(base: *const u8, at: usize) -> Value {
+    (base.add(at) as *const Value).read_unaligned()
+}
+
+/// Write `value` at byte `at` of the memory at `base`.
+///
+/// # Safety
+///
+/// The value must be inside the memory.
+#[inline(always)]
+unsafe fn store(base: *mut u8, at: usize, value: Value) {
+    (base.add(at) as *mut Value).write_unaligned(value)
+}
@@ -0,0 +1,500 @@
+//! The `.chsb` file format of `Bytecode`, so a program can be compiled once
+//! and run many times.
+//!
+//! All numbers are little endian. A file is, in order:
+//!
+//! - the magic `CHSB`, then the format `VERSION` as a `u16` and the flags as
+//!   a `u16`. Flag `DEBUG_INFO` is set when the file has source locations.
+//! - `entry` and `program_mem` as `u64`s.
+//! - the string table: a `u32` count, then each string as a `u32` length and
+//!   its bytes.
+//! - the host functions: a `u32` count, then each name as a string.
+//! - the exports: a `u32` count, then each one as its name, its address as a
+//!   `u64` and its signature as two lists of types. A list of types is a `u8`
+//!   count followed by one `u8` per type.
+//! - the instructions: a `u32` count, then each one as a `u8` opcode followed
+//!   by its operand, if any, as an `i64`.
+//! - with `DEBUG_INFO`, the line and column of each instruction as `u32`s.
+//!
+//! Loading checks that the file is whole, that every address and index in it
+//! points inside the program, that binds are at most `MAX_BINDS` deep and
+//! that it asks for at most `MAX_MEMORY` bytes.
+//!
+//! ```
+//! use chs_parser::parse_program;
+//! use chs_vm_v2::{compiler::compile, instructions::Bytecode, Vm};
+//!
+//! let program = parse_program("fn main : -> int { 6 7 * }", "main.chs").unwrap();
+//! let bytes = compile(&program).to_bytes();
+//! let bytecode = Bytecode::from_bytes(&bytes).unwrap();
+//! assert_eq!(Vm::new(bytecode).run().unwrap().code, 42);
+//! ```
+//!
+//! Files that the VM could not run are rejected:
+//!
+//! ```
+//! use chs_vm_v2::{format::LoadError, instructions::{Bytecode, Instr}};
+//!
+//! let mut program = Bytecode::new(vec![Instr::PushI32(1), Instr::Trunc(0), Instr::Drop], 0);
+//! let err = Bytecode::from_bytes(&program.to_bytes()).unwrap_err();
+//! assert_eq!(err, LoadError::BadOperand(1));
+//!
+//! program.program[1] = Instr::Trunc(8);
+//! program.program_mem = usize::MAX;
+//! let err = Bytecode::from_bytes(&program.to_bytes()).unwrap_err();
+//! assert_eq!(err, LoadError::TooMuchMemory(usize::MAX));
+//!
+//! program.program_mem = 0;
+//! program.program[1] = Instr::UnBind(usize::MAX);
+//! let err = Bytecode::from_bytes(&program.to_bytes()).unwrap_err();
+//! assert_eq!(err, LoadError::BadOperand(1));
+//! ```
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use chs_parser::{DataType, Loc, Signature, Syscall};
+
+use crate::{
+    instructions::{Bytecode, Export, Instr},
+    Value,
+};
+
+pub const MAGIC: &[u8; 4] = b"CHSB";
+/// Changes whenever the layout of the file or the opcodes change. Version 2
+/// made `PushBind` count from the binds of the running call.
+pub const VERSION: u16 = 2;
+pub const DEBUG_INFO: u16 = 1;
+/// Bytes of memory, for its strings and allocations, a file can ask for.
+pub const MAX_MEMORY: usize = 1 << 30;
+/// Let binds a `LetBind`, `PushBind` or `UnBind` can refer to, as many as
+/// would fit in `MAX_MEMORY`. No return stack holds more.
+pub const MAX_BINDS: usize = MAX_MEMORY / size_of::<Value>();
+
+/// Instructions without an operand. The opcode of each is its index.
+const SIMPLE: [Instr; 34] = [
+    Instr::Halt,
+    Instr::Drop,
+    Instr::Dup,
+    Instr::Rot,
+    Instr::Over,
+    Instr::Swap,
+    Instr::Debug,
+    Instr::PlusI,
+    Instr::MinusI,
+    Instr::MultI,
+    Instr::DivI,
+    Instr::DivS,
+    Instr::Mod,
+    Instr::ModS,
+    Instr::Offset,
+    Instr::Lt,
+    Instr::Gt,
+    Instr::Le,
+    Instr::Ge,
+    Instr::LtS,
+    Instr::GtS,
+    Instr::LeS,
+    Instr::GeS,
+    Instr::EqI,
+    Instr::NEqI,
+    Instr::BitAnd,
+    Instr::BitOr,
+    Instr::BitXor,
+    Instr::BitNot,
+    Instr::Shl,
+    Instr::Shr,
+    Instr::Sar,
+    Instr::Not,
+    Instr::Ret,
+];
+
+/// Opcodes of the instructions with an operand, after the simple ones.
+const LET_BIND: u8 = 64;
+const PUSH_BIND: u8 = 65;
+const UN_BIND: u8 = 66;
+const SYS: u8 = 67;
+const WRITE: u8 = 68;
+const READ: u8 = 69;
+const CALL: u8 = 70;
+const NATIVE: u8 = 71;
+const BIND: u8 = 72;
+const TRUNC: u8 = 73;
+const SIGN_EXT: u8 = 74;
+const PUSH_I32: u8 = 75;
+const PUSH_PTR: u8 = 76;
+const JMP: u8 = 77;
+const JMP_IF: u8 = 78;
+const A_JMP: u8 = 79;
+const A_JMP_IF: u8 = 80;
+
+/// Type codes in signatures. The code of each is its index.
+const TYPES: [DataType; 11] = [
+    DataType::Int,
+    DataType::Ptr,
+    DataType::Bool,
+    DataType::Char,
+    DataType::U8,
+    DataType::U16,
+    DataType::U32,
+    DataType::U64,
+    DataType::I8,
+    DataType::I16,
+    DataType::I32,
+];
+
+/// Why a file is not a program that can be loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// The file ends, after this many bytes, in the middle of something.
+    Truncated(usize),
+    TrailingBytes(usize),
+    BadOpcode(u8),
+    BadType(u8),
+    BadSyscall(i64),
+    BadString,
+    /// Instruction `ip`, or the entry when it is the end of the program,
+    /// refers to something that is not there.
+    OutOfRange(usize),
+    /// Instruction `ip` has an operand no instruction of its kind can have.
+    BadOperand(usize),
+    BadExport(String),
+    /// The program asks for this many bytes of memory, more than
+    /// `MAX_MEMORY`, or for more than there are addresses when it is
+    /// `usize::MAX`.
+    TooMuchMemory(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "Not a chs bytecode file"),
+            LoadError::UnsupportedVersion(v) => write!(
+                f,
+                "Bytecode version {} is not supported, expected {}",
+                v, VERSION
+            ),
+            LoadError::Truncated(at) => write!(f, "Bytecode ends unexpectedly after {} bytes", at),
+            LoadError::TrailingBytes(at) => write!(f, "Unexpected data after byte {}", at),
+            LoadError::BadOpcode(op) => write!(f, "Unknown opcode {}", op),
+            LoadError::BadType(t) => write!(f, "Unknown type code {}", t),
+            LoadError::BadSyscall(s) => write!(f, "Unknown syscall code {}", s),
+            LoadError::BadString => write!(f, "Name is not valid UTF-8"),
+            LoadError::OutOfRange(ip) => {
+                write!(f, "Instruction {} refers outside of the program", ip)
+            }
+            LoadError::BadOperand(ip) => write!(f, "Instruction {} has a bad operand", ip),
+            LoadError::BadExport(name) => write!(f, "Export `{}` is outside of the program", name),
+            LoadError::TooMuchMemory(size) => write!(
+                f,
+                "Program needs {} bytes of memory, more than {}",
+                size, MAX_MEMORY
+            ),
+        }
+    }
+}
+
+impl Bytecode {
+    /// Whether `bytes` start like a bytecode file rather than source.
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        let debug = !self.locs.is_empty();
+        w.u16(if debug { DEBUG_INFO } else { 0 });
+        w.u64(self.entry as u64);
+        w.u64(self.program_mem as u64);
+        w.u32(self.strs.len() as u32);
+        for s in &self.strs {
+            w.blob(s);
+        }
+        w.u32(self.natives.len() as u32);
+        for name in &self.natives {
+            w.blob(name.as_bytes());
+        }
+        // Sorted, so that the same program always gives the same file.
+        let mut exports: Vec<_> = self.exports.iter().collect();
+        exports.sort_by_key(|(name, _)| *name);
+        w.u32(exports.len() as u32);
+        for (name, export) in exports {
+            w.blob(name.as_bytes());
+            w.u64(export.addr as u64);
+            w.types(&export.signature.ins);
+            w.types(&export.signature.outs);
+        }
+        w.u32(self.program.len() as u32);
+        for instr in &self.program {
+            w.instr(instr);
+        }
+        if debug {
+            for ip in 0..self.program.len() {
+                let loc = self.loc(ip).unwrap_or_default();
+                w.u32(loc.line() as u32);
+                w.u32(loc.col() as u32);
+            }
+        }
+        w.buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(LoadError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let flags = r.u16()?;
+        let entry = r.u64()? as usize;
+        let program_mem = r.u64()? as usize;
+        let mut strs = vec![];
+        for _ in 0..r.u32()? {
+            strs.push(Rc::from(r.blob()?));
+        }
+        let mut natives = vec![];
+        for _ in 0..r.u32()? {
+            natives.push(r.string()?);
+        }
+        let mut exports = HashMap::new();
+        for _ in 0..r.u32()? {
+            let name = r.string()?;
+            let addr = r.u64()? as usize;
+            let ins = r.types()?;
+            let outs = r.types()?;
+            let signature = Signature::new(&ins, &outs);
+            exports.insert(name, Export { addr, signature });
+        }
+        let mut program = vec![];
+        for _ in 0..r.u32()? {
+            program.push(r.instr()?);
+        }
+        let mut locs = vec![];
+        if flags & DEBUG_INFO != 0 {
+            for _ in 0..program.len() {
+                let line = r.u32()? as usize;
+                let col = r.u32()? as usize;
+                locs.push(Loc::new(line, col));
+            }
+        }
+        if r.pos != bytes.len() {
+            return Err(LoadError::TrailingBytes(r.pos));
+        }
+        let bytecode = Bytecode {
+            program,
+            locs,
+            program_mem,
+            entry,
+            strs,
+            exports,
+            natives,
+        };
+        bytecode.validate()?;
+        Ok(bytecode)
+    }
+
+    /// Check that every address and index points inside the program and
+    /// that operands are in range, so that a bad file cannot make the VM
+    /// read past it or fail on arithmetic.
+    fn validate(&self) -> Result<(), LoadError> {
+        let len = self.program.len();
+        if self.entry > len {
+            return Err(LoadError::OutOfRange(len));
+        }
+        for (name, export) in &self.exports {
+            if export.addr >= len {
+                return Err(LoadError::BadExport(name.clone()));
+            }
+        }
+        let mem_size = self
+            .strs
+            .iter()
+            .try_fold(self.program_mem, |size, s| size.checked_add(s.len()))
+            .ok_or(LoadError::TooMuchMemory(usize::MAX))?;
+        if mem_size > MAX_MEMORY {
+            return Err(LoadError::TooMuchMemory(mem_size));
+        }
+        for (ip, instr) in self.program.iter().enumerate() {
+            let ok = match *instr {
+                Instr::Call(addr) => addr < len,
+                Instr::AJmp(addr) | Instr::AJmpIf(addr) => addr <= len,
+                Instr::Jmp(rel) | Instr::JmpIf(rel) => (ip as isize)
+                    .checked_add(rel)
+                    .is_some_and(|addr| (0..=len as isize).contains(&addr)),
+                Instr::Native(i) => i < self.natives.len(),
+                Instr::PushPtr(addr) => addr <= mem_size,
+                _ => true,
+            };
+            if !ok {
+                return Err(LoadError::OutOfRange(ip));
+            }
+            let ok = match *instr {
+                // Casts are to a width of 1 to 64 bits.
+                Instr::Trunc(bits) | Instr::SignExt(bits) => (1..=64).contains(&bits),
+                Instr::LetBind(n) | Instr::PushBind(n) | Instr::UnBind(n) => n <= MAX_BINDS,
+                _ => true,
+            };
+            if !ok {
+                return Err(LoadError::BadOperand(ip));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn blob(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+    fn types(&mut self, types: &[DataType]) {
+        self.buf.push(types.len() as u8);
+        for typ in types {
+            let code = TYPES
+                .iter()
+                .position(|t| t == typ)
+                .expect("every type has a code");
+            self.buf.push(code as u8);
+        }
+    }
+    fn instr(&mut self, instr: &Instr) {
+        if let Some(op) = SIMPLE.iter().position(|i| i == instr) {
+            self.buf.push(op as u8);
+            return;
+        }
+        let (op, operand) = match *instr {
+            Instr::LetBind(n) => (LET_BIND, n as i64),
+            Instr::PushBind(n) => (PUSH_BIND, n as i64),
+            Instr::UnBind(n) => (UN_BIND, n as i64),
+            Instr::Sys(call) => {
+                let code = Syscall::all().position(|c| c == call);
+                (SYS, code.expect("every syscall has a code") as i64)
+            }
+            Instr::Write(n) => (WRITE, n as i64),
+            Instr::Read(n) => (READ, n as i64),
+            Instr::Call(addr) => (CALL, addr as i64),
+            Instr::Native(i) => (NATIVE, i as i64),
+            Instr::Bind(n) => (BIND, n as i64),
+            Instr::Trunc(n) => (TRUNC, n as i64),
+            Instr::SignExt(n) => (SIGN_EXT, n as i64),
+            Instr::PushI32(v) => (PUSH_I32, v as i64),
+            Instr::PushPtr(addr) => (PUSH_PTR, addr as i64),
+            Instr::Jmp(rel) => (JMP, rel as i64),
+            Instr::JmpIf(rel) => (JMP_IF, rel as i64),
+            Instr::AJmp(addr) => (A_JMP, addr as i64),
+            Instr::AJmpIf(addr) => (A_JMP_IF, addr as i64),
+            _ => unreachable!("{:?} is in `SIMPLE`", instr),
+        };
+        self.buf.push(op);
+        self.bytes(&operand.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.pos.saturating_add(n);
+        let Some(bytes) = self.bytes.get(self.pos..end) else {
+            return Err(LoadError::Truncated(self.bytes.len()));
+        };
+        self.pos = end;
+        Ok(bytes)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+    fn i64(&mut self) -> Result<i64, LoadError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+    fn blob(&mut self) -> Result<&'a [u8], LoadError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    fn string(&mut self) -> Result<String, LoadError> {
+        let bytes = self.blob()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LoadError::BadString)
+    }
+    fn types(&mut self) -> Result<Vec<DataType>, LoadError> {
+        let len = self.u8()?;
+        (0..len)
+            .map(|_| {
+                let code = self.u8()?;
+                TYPES
+                    .get(code as usize)
+                    .copied()
+                    .ok_or(LoadError::BadType(code))
+            })
+            .collect()
+    }
+    fn instr(&mut self) -> Result<Instr, LoadError> {
+        let op = self.u8()?;
+        if let Some(instr) = SIMPLE.get(op as usize) {
+            return Ok(instr.clone());
+        }
+        if !(LET_BIND..=A_JMP_IF).contains(&op) {
+            return Err(LoadError::BadOpcode(op));
+        }
+        let v = self.i64()?;
+        let instr = match op {
+            LET_BIND => Instr::LetBind(v as usize),
+            PUSH_BIND => Instr::PushBind(v as usize),
+            UN_BIND => Instr::UnBind(v as usize),
+            SYS => {
+                let call = usize::try_from(v).ok().and_then(|i| Syscall::all().nth(i));
+                Instr::Sys(call.ok_or(LoadError::BadSyscall(v))?)
+            }
+            WRITE => Instr::Write(v as usize),
+            READ => Instr::Read(v as usize),
+            CALL => Instr::Call(v as usize),
+            NATIVE => Instr::Native(v as usize),
+            BIND => Instr::Bind(v as u32),
+            TRUNC => Instr::Trunc(v as u32),
+            SIGN_EXT => Instr::SignExt(v as u32),
+            PUSH_I32 => Instr::PushI32(v as i32),
+            PUSH_PTR => Instr::PushPtr(v as usize),
+            JMP => Instr::Jmp(v as isize),
+            JMP_IF => Instr::JmpIf(v as isize),
+            A_JMP => Instr::AJmp(v as usize),
+            A_JMP_IF => Instr::AJmpIf(v as usize),
+            _ => unreachable!("checked above"),
+        };
+        Ok(instr)
+    }
+}
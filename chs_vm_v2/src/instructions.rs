@@ -1,5 +1,15 @@
-use std::rc::Rc;
+use std::{collections::HashMap, rc::Rc};
 
+use chs_parser::{Loc, Signature, Syscall};
+
+/// Values on the stack are 64-bit words. Signed integers are kept sign
+/// extended and unsigned ones zero extended, so that the 64-bit instructions
+/// give the right result for every width once `Trunc`/`SignExt` bring it back
+/// to the width of its type.
+///
+/// Arithmetic and `Shl` wrap around. Shifting by 64 or more gives 0, or -1
+/// for `Sar` of a negative value. Dividing by zero stops the program.
+/// Instructions ending in `S` treat their operands as signed.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Instr {
     Halt,
@@ -10,21 +20,43 @@ pub enum Instr {
     Swap,
     Debug,
     PlusI,
+    MinusI,
     MultI,
+    DivI,
+    DivS,
     Mod,
+    ModS,
     Offset,
     Lt,
+    Gt,
+    Le,
+    Ge,
+    LtS,
+    GtS,
+    LeS,
+    GeS,
     EqI,
     NEqI,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    Sar,
+    Not,
     Ret,
     LetBind(usize),
     PushBind(usize),
     UnBind(usize),
-    Sys(String),    // SysFn // TODO: Make String -> Enum
+    Sys(Syscall),
     Write(usize),   // Bytes
     Read(usize),    // Bytes
     Call(usize),    // addr
+    Native(usize),  // Index in `Bytecode::natives`
     Bind(u32),      // Relative Position
+    Trunc(u32),     // Bits to keep
+    SignExt(u32),   // Bits of the signed value
     PushI32(i32),   // Immediate
     PushPtr(usize), // Ptr
     Jmp(isize),     // Relative Address
@@ -33,24 +65,45 @@ pub enum Instr {
     AJmpIf(usize),  // Abslute Address
 }
 
+/// A function of the program that the host can call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub addr: usize,
+    pub signature: Signature,
+}
+
 #[derive(Debug, Clone)]
 pub struct Bytecode {
     pub program: Vec<Instr>,
+    /// Source location of each instruction, when known.
+    pub locs: Vec<Loc>,
     pub program_mem: usize,
     pub entry: usize,
     pub strs: Vec<Rc<[u8]>>,
+    pub exports: HashMap<String, Export>,
+    /// Names of the host functions the program calls.
+    pub natives: Vec<String>,
 }
 
 impl Bytecode {
     pub fn new(program: Vec<Instr>, program_mem: usize) -> Self {
         Self {
             program,
+            locs: Vec::default(),
             program_mem,
             entry: 0,
             strs: Vec::default(),
+            exports: HashMap::default(),
+            natives: Vec::default(),
         }
     }
     pub fn len(&self) -> usize {
         self.program.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.program.is_empty()
+    }
+    pub fn loc(&self, ip: usize) -> Option<Loc> {
+        self.locs.get(ip).copied()
+    }
 }
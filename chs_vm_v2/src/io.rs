@@ -0,0 +1,144 @@
+//! The file descriptors of a program. The host decides what each one is, so
+//! a program cannot touch the fds of the process running it.
+//!
+//! ```
+//! use chs_parser::parse_program;
+//! use chs_vm_v2::{compiler::compile, io::Stream, Vm};
+//!
+//! let program = parse_program("\"hi\\n\" 1 $write", "hi.chs").unwrap();
+//! let mut vm = Vm::new(compile(&program));
+//! let out = Stream::buffer();
+//! vm.fds_mut().set(1, out.clone());
+//! vm.run().unwrap();
+//! assert_eq!(out.contents(), b"hi\n");
+//! ```
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+/// What a file descriptor reads from and writes to.
+#[derive(Debug, Clone)]
+pub enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+    /// Kept in memory, for the host to fill or read. Writes go at the end
+    /// and reads take from the start. Clones share the same buffer.
+    Buffer(Rc<RefCell<Vec<u8>>>),
+    File(Rc<RefCell<File>>),
+}
+
+impl Stream {
+    pub fn buffer() -> Self {
+        Stream::Buffer(Rc::default())
+    }
+    /// A buffer to read `bytes` from.
+    pub fn input(bytes: &[u8]) -> Self {
+        Stream::Buffer(Rc::new(RefCell::new(bytes.to_vec())))
+    }
+    pub fn file(file: File) -> Self {
+        Stream::File(Rc::new(RefCell::new(file)))
+    }
+    /// What is left in a `Buffer`; nothing for the other streams.
+    pub fn contents(&self) -> Vec<u8> {
+        match self {
+            Stream::Buffer(buf) => buf.borrow().clone(),
+            _ => vec![],
+        }
+    }
+    fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Stream::Stdin => Err(io::ErrorKind::Unsupported.into()),
+            Stream::Stdout => {
+                let mut out = io::stdout().lock();
+                out.write_all(bytes)?;
+                out.flush()
+            }
+            Stream::Stderr => io::stderr().write_all(bytes),
+            Stream::Buffer(buf) => {
+                buf.borrow_mut().extend_from_slice(bytes);
+                Ok(())
+            }
+            Stream::File(file) => file.borrow_mut().write_all(bytes),
+        }
+    }
+    fn read(&self, bytes: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Stdin => io::stdin().read(bytes),
+            Stream::Stdout | Stream::Stderr => Err(io::ErrorKind::Unsupported.into()),
+            Stream::Buffer(buf) => {
+                let mut buf = buf.borrow_mut();
+                let n = bytes.len().min(buf.len());
+                bytes[..n].copy_from_slice(&buf[..n]);
+                buf.drain(..n);
+                Ok(n)
+            }
+            Stream::File(file) => file.borrow_mut().read(bytes),
+        }
+    }
+}
+
+/// File descriptors of a `Vm`. At first 0 is stdin, 1 is stdout and 2 is
+/// stderr.
+#[derive(Debug, Clone)]
+pub struct FdTable {
+    fds: Vec<Option<Stream>>,
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self {
+            fds: vec![
+                Some(Stream::Stdin),
+                Some(Stream::Stdout),
+                Some(Stream::Stderr),
+            ],
+        }
+    }
+}
+
+impl FdTable {
+    /// Make `fd` read from and write to `stream`.
+    pub fn set(&mut self, fd: usize, stream: Stream) {
+        if fd >= self.fds.len() {
+            self.fds.resize(fd + 1, None);
+        }
+        self.fds[fd] = Some(stream);
+    }
+    /// Open `stream` on the lowest fd that is not open.
+    pub fn open(&mut self, stream: Stream) -> usize {
+        let fd = self
+            .fds
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.fds.len());
+        self.set(fd, stream);
+        fd
+    }
+    /// Returns whether `fd` was open.
+    pub fn close(&mut self, fd: usize) -> bool {
+        match self.fds.get_mut(fd) {
+            Some(slot) => slot.take().is_some(),
+            None => false,
+        }
+    }
+    pub fn get(&self, fd: usize) -> Option<&Stream> {
+        self.fds.get(fd)?.as_ref()
+    }
+    fn get_fd(&self, fd: u64) -> Option<&Stream> {
+        self.get(usize::try_from(fd).ok()?)
+    }
+    /// Write all of `bytes` to `fd`. `None` when it is not open; errors of the
+    /// real streams are ignored, like a closed pipe.
+    pub(crate) fn write(&self, fd: u64, bytes: &[u8]) -> Option<()> {
+        let _ = self.get_fd(fd)?.write_all(bytes);
+        Some(())
+    }
+    /// Read into `bytes` from `fd`. `None` when it is not open.
+    pub(crate) fn read(&self, fd: u64, bytes: &mut [u8]) -> Option<io::Result<usize>> {
+        Some(self.get_fd(fd)?.read(bytes))
+    }
+}
@@ -1,13 +1,28 @@
+mod arith;
 pub mod compiler;
+pub mod disasm;
+mod fast;
+pub mod format;
 pub mod instructions;
+pub mod io;
+pub mod natives;
+pub mod optimize;
+pub mod sys;
+pub mod trace;
+pub mod verify;
 use core::fmt;
-use std::{
-    fs::File, io::Write, marker::PhantomData, os::fd::FromRawFd, process::exit,
-    ptr::slice_from_raw_parts,
-};
+use std::{marker::PhantomData, ptr::slice_from_raw_parts, time::Instant};
 
+use arith::{binary, unary};
+use chs_parser::{Loc, Syscall};
+use fast::Op;
+use format::MAX_MEMORY;
 use instructions::{Bytecode, Instr};
-use memory::Memory;
+use io::FdTable;
+use memory::{Memory, MemoryAllowed};
+use natives::Natives;
+use sys::Policy;
+use trace::Tracer;
 
 pub fn jump(addr: usize, rel: isize) -> usize {
     (addr as isize + rel) as usize
@@ -18,6 +33,170 @@ pub fn jump_to(addr: usize, other: usize) -> isize {
 
 type Value = u64;
 
+/// Why a program was stopped before its end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrapKind {
+    StackUnderflow,
+    StackOverflow,
+    ReturnStackUnderflow,
+    ReturnStackOverflow,
+    /// Access of `len` bytes at `addr`, past the end of memory.
+    OutOfBounds {
+        addr: u64,
+        len: usize,
+    },
+    /// `@` or `!` of a number of bits other than 8, 16, 32 or 64.
+    InvalidWidth(usize),
+    DivisionByZero,
+    BadFd(i64),
+    /// The program ran all the instructions `Limits::fuel` allowed.
+    OutOfFuel,
+    /// The program needs more memory than `Limits::memory`.
+    MemoryLimit {
+        needed: usize,
+        limit: usize,
+    },
+    /// A syscall the policy of the VM does not allow.
+    Denied(Syscall),
+    /// A host function the program calls was not registered.
+    UnboundNative(String),
+    /// A host function failed, or returned a number of values other than its
+    /// signature says.
+    Native(String, String),
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapKind::StackUnderflow => write!(f, "Stack underflow"),
+            TrapKind::StackOverflow => write!(f, "Stack overflow"),
+            TrapKind::ReturnStackUnderflow => write!(f, "Return stack underflow"),
+            TrapKind::ReturnStackOverflow => write!(f, "Return stack overflow"),
+            TrapKind::OutOfBounds { addr, len } => {
+                write!(f, "Out of bounds access of {} bytes at {}", len, addr)
+            }
+            TrapKind::InvalidWidth(bits) => write!(f, "Invalid memory access width {}", bits),
+            TrapKind::DivisionByZero => write!(f, "Division by zero"),
+            TrapKind::BadFd(fd) => write!(f, "Bad file descriptor {}", fd),
+            TrapKind::OutOfFuel => write!(f, "Out of fuel"),
+            TrapKind::MemoryLimit { needed, limit } => write!(
+                f,
+                "Program needs {} bytes of memory, more than the limit of {}",
+                needed, limit
+            ),
+            TrapKind::Denied(call) => write!(f, "Syscall `${}` is not allowed", call),
+            TrapKind::UnboundNative(name) => write!(f, "Native fn `{}` is not registered", name),
+            TrapKind::Native(name, msg) => write!(f, "{} in native fn `{}`", msg, name),
+        }
+    }
+}
+
+/// A program stopped by an error at run time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    /// The instruction that failed.
+    pub ip: usize,
+    /// Source location of the instruction, if the bytecode has it.
+    pub loc: Option<Loc>,
+    /// The data stack at the trap, bottom first. Operands the instruction
+    /// had already taken are not on it.
+    pub stack: Vec<u64>,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at instruction {}", self.kind, self.ip)
+    }
+}
+
+/// Why `Vm::call` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallError {
+    UnknownFunction(String),
+    Arity {
+        expected: usize,
+        got: usize,
+    },
+    Trap(Trap),
+    /// The function called `$exit` with this code.
+    Exited(i32),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::UnknownFunction(name) => write!(f, "Unknown fn `{}`", name),
+            CallError::Arity { expected, got } => {
+                write!(f, "Expected {} arguments but got {}", expected, got)
+            }
+            CallError::Trap(trap) => write!(f, "{}", trap),
+            CallError::Exited(code) => write!(f, "Exited with code {}", code),
+        }
+    }
+}
+
+impl From<Trap> for CallError {
+    fn from(trap: Trap) -> Self {
+        CallError::Trap(trap)
+    }
+}
+
+/// How a program that ran to its end finished. The code is the one given to
+/// `$exit`, else the `int` returned by `main`, else 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExitStatus {
+    pub code: i32,
+}
+
+/// What a program may use, for running code that is not trusted. Going over
+/// a limit stops the program with a trap.
+///
+/// ```
+/// use chs_parser::parse_program;
+/// use chs_vm_v2::{compiler::compile, natives::Natives, Limits, TrapKind, Vm};
+///
+/// let program = parse_program("0 while dup 1000 < { 1 + } $exit", "count.chs").unwrap();
+/// let limits = Limits { fuel: Some(100), ..Limits::default() };
+/// let mut vm = Vm::with_limits(compile(&program), Natives::default(), limits).unwrap();
+/// let status = loop {
+///     match vm.run() {
+///         Ok(status) => break status,
+///         Err(trap) if trap.kind == TrapKind::OutOfFuel => vm.add_fuel(100),
+///         Err(trap) => panic!("{}", trap.kind),
+///     }
+/// };
+/// assert_eq!(status.code, 1000);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions the program may run, or no limit. A program that ran out
+    /// can go on after `Vm::add_fuel`.
+    pub fuel: Option<u64>,
+    /// Values the data stack holds.
+    pub stack: usize,
+    /// Values the return stack holds, two for each call. The let binds of
+    /// all calls can hold as many.
+    pub return_stack: usize,
+    /// Bytes of memory for the strings and allocations of the program, or
+    /// `format::MAX_MEMORY`.
+    pub memory: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: None,
+            stack: 128,
+            return_stack: 128,
+            memory: None,
+        }
+    }
+}
+
+/// A stack of values that grows down from the end of `data`. The last value
+/// of `data` is not part of it: it is where the fast loop puts the cached top
+/// of an empty stack.
 #[derive(Debug)]
 struct VMStack<T: Sized> {
     marker: PhantomData<T>,
@@ -28,184 +207,294 @@ struct VMStack<T: Sized> {
 impl fmt::Display for VMStack<Value> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut buf = String::from("[");
-        let mut i = self.data.size() - size_of::<Value>();
-        while i >= self.top {
-            buf.push_str(&format!(" {} ", self.data.read::<Value>(i)));
-            i -= size_of::<Value>()
+        for value in self.values() {
+            // Most values are `int`s, so show them signed.
+            buf.push_str(&format!(" {} ", value as i64));
         }
         write!(f, "{}]", buf)
     }
 }
 
 impl VMStack<Value> {
+    /// A stack of `size` bytes.
     pub fn new(size: usize) -> Self {
-        let data = Memory::new(size);
-        let top = size; // - size_of::<Value>();
+        let data = Memory::new(size.saturating_add(size_of::<Value>()));
+        let top = data.size() - size_of::<Value>();
         Self {
             data,
             top,
-            marker: PhantomData::default(),
+            marker: PhantomData,
         }
     }
-    pub fn get(&mut self, index: usize) -> Value {
-        let index = index * size_of::<Value>();
-        self.data.read(index)
-    }
-    pub fn push(&mut self, value: Value) {
-        self.top -= size_of::<Value>();
+    pub fn push(&mut self, value: Value) -> Option<()> {
+        self.top = self.top.checked_sub(size_of::<Value>())?;
         self.data.write(self.top, value);
-        self.data.set_write_pos(self.top);
+        Some(())
     }
-    pub fn pop(&mut self) -> Value {
+    pub fn pop(&mut self) -> Option<Value> {
         let index = self.top;
+        if index >= self.bottom() {
+            return None;
+        }
         self.top += size_of::<Value>();
-        self.data.read(index)
+        Some(self.data.read(index))
+    }
+    /// The `n`th value from the top, starting at 1.
+    pub fn peek(&self, n: usize) -> Option<Value> {
+        if n == 0 || n > self.depth() {
+            return None;
+        }
+        Some(self.data.read(self.top + (n - 1) * size_of::<Value>()))
+    }
+    /// Number of values on the stack.
+    pub fn depth(&self) -> usize {
+        (self.bottom() - self.top) / size_of::<Value>()
+    }
+    /// Where the first value goes.
+    fn bottom(&self) -> usize {
+        self.data.size() - size_of::<Value>()
+    }
+    /// Drop values down to `depth` of them.
+    pub fn truncate(&mut self, depth: usize) {
+        while self.depth() > depth {
+            self.top += size_of::<Value>();
+        }
     }
-    pub fn len(&self) -> usize {
-        self.data.size() / size_of::<Value>()
+    /// Values on the stack, bottom first.
+    pub fn values(&self) -> Vec<Value> {
+        (1..=self.depth())
+            .rev()
+            .filter_map(|n| self.peek(n))
+            .collect()
     }
 }
 
-pub fn vm_run(program: Bytecode) {
-    let mut strs_size = 0;
-    for e in program.strs.iter() {
-        strs_size += e.len();
+/// State of a running program.
+///
+/// Each call pushes a frame of two values to `rstack`: the address to return
+/// to and the `frame` of the caller. The let binds of a call are on `locals`
+/// from its `frame` up, and `PushBind` counts from there, so a call cannot
+/// see the binds of its caller and returning drops its own.
+struct Machine {
+    stack: VMStack<Value>,
+    rstack: VMStack<Value>,
+    locals: VMStack<Value>,
+    /// Depth of `locals` where the binds of the running call start.
+    frame: usize,
+    mem: Memory,
+    natives: Natives,
+    fds: FdTable,
+    policy: Policy,
+    args: Vec<String>,
+    start: Instant,
+    /// Code given to `$exit`, which stops the program.
+    exit: Option<i32>,
+}
+
+impl Machine {
+    fn push(&mut self, value: Value) -> Result<(), TrapKind> {
+        self.stack.push(value).ok_or(TrapKind::StackOverflow)
+    }
+    fn pop(&mut self) -> Result<Value, TrapKind> {
+        self.stack.pop().ok_or(TrapKind::StackUnderflow)
+    }
+    fn rpush(&mut self, value: Value) -> Result<(), TrapKind> {
+        self.rstack.push(value).ok_or(TrapKind::ReturnStackOverflow)
+    }
+    fn rpop(&mut self) -> Result<Value, TrapKind> {
+        self.rstack.pop().ok_or(TrapKind::ReturnStackUnderflow)
     }
-    let mut stack = VMStack::<Value>::new(1024);
-    let mut rstack = VMStack::<Value>::new(1024);
-    let mut mem = Memory::new(strs_size + program.program_mem);
-    mem.set_write_pos(0);
-    for e in program.strs.iter() {
-        for v in e.iter() {
-            mem.write_push::<u8>(*v)
+    /// Start a call that returns to `ret`.
+    fn enter(&mut self, ret: usize) -> Result<(), TrapKind> {
+        self.rpush(ret as Value)?;
+        self.rpush(self.frame as Value)?;
+        self.frame = self.locals.depth();
+        Ok(())
+    }
+    /// End the running call, dropping its binds, and return where to.
+    fn leave(&mut self) -> Result<usize, TrapKind> {
+        let frame = self.rpop()? as usize;
+        let ret = self.rpop()? as usize;
+        self.locals.truncate(self.frame);
+        self.frame = frame;
+        Ok(ret)
+    }
+    /// Check that `len` bytes at `addr` are in memory.
+    fn bounds(&self, addr: Value, len: usize) -> Result<usize, TrapKind> {
+        match usize::try_from(addr) {
+            Ok(index)
+                if index
+                    .checked_add(len)
+                    .is_some_and(|end| end <= self.mem.size()) =>
+            {
+                Ok(index)
+            }
+            _ => Err(TrapKind::OutOfBounds { addr, len }),
         }
     }
-    let mut ip = program.entry;
-    // for p in &program.program { println!("{:?}", p) }
-    loop {
-        if ip >= program.program.len() {
-            break;
+    fn bytes(&self, addr: Value, len: usize) -> Result<&[u8], TrapKind> {
+        let index = self.bounds(addr, len)?;
+        Ok(unsafe { &*slice_from_raw_parts(self.mem.inner.add(index), len) })
+    }
+    fn read<T: Copy + MemoryAllowed>(&self, addr: Value) -> Result<T, TrapKind> {
+        let index = self.bounds(addr, size_of::<T>())?;
+        Ok(self.mem.read(index))
+    }
+    fn write<T: Copy + MemoryAllowed>(&mut self, addr: Value, value: T) -> Result<(), TrapKind> {
+        let index = self.bounds(addr, size_of::<T>())?;
+        self.mem.write(index, value);
+        Ok(())
+    }
+    fn binop(&mut self, op: impl FnOnce(Value, Value) -> Value) -> Result<(), TrapKind> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push(op(a, b))
+    }
+    /// Like `binop`, for division and remainder. The operands are left on the
+    /// stack when the divisor is zero.
+    fn divop(&mut self, op: impl FnOnce(Value, Value) -> Value) -> Result<(), TrapKind> {
+        if self.stack.depth() >= 2 && self.stack.peek(1) == Some(0) {
+            return Err(TrapKind::DivisionByZero);
+        }
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push(op(a, b))
+    }
+    fn unop(&mut self, op: impl FnOnce(Value) -> Value) -> Result<(), TrapKind> {
+        let a = self.pop()?;
+        self.push(op(a))
+    }
+
+    fn call_native(&mut self, name: &str) -> Result<(), TrapKind> {
+        let native = self
+            .natives
+            .get_mut(name)
+            .ok_or_else(|| TrapKind::UnboundNative(name.to_string()))?;
+        let ins = native.signature.ins.len();
+        let outs = native.signature.outs.len();
+        if self.stack.depth() < ins {
+            return Err(TrapKind::StackUnderflow);
+        }
+        let mut args = vec![0; ins];
+        for arg in args.iter_mut().rev() {
+            *arg = self.stack.pop().ok_or(TrapKind::StackUnderflow)?;
+        }
+        let results = (native.func)(&args).map_err(|msg| TrapKind::Native(name.into(), msg))?;
+        if results.len() != outs {
+            let msg = format!("Expected {} results but got {}", outs, results.len());
+            return Err(TrapKind::Native(name.into(), msg));
+        }
+        for value in results {
+            self.push(value)?;
         }
+        Ok(())
+    }
+
+    /// Run the instruction at `ip` and return the address of the next one.
+    fn step(&mut self, program: &Bytecode, ip: usize) -> Result<usize, TrapKind> {
         let mut next_addr = ip + 1;
         match program.program[ip] {
             Instr::Halt => {
                 next_addr = program.program.len();
             }
-            Instr::Sys(ref s) => match s.as_str() {
-                "write" => {
-                    let c = stack.pop() as i32; // fd
-                    let b = stack.pop() as usize; // ptr
-                    let a = stack.pop() as usize; // int
-                    mem.set_write_pos(b);
-                    let buf = unsafe { &*slice_from_raw_parts(mem.to_ptr::<u8>(), a) };
-                    let mut f = unsafe { File::from_raw_fd(c) };
-                    match f.metadata() {
-                        Ok(_) => {}
-                        Err(err) => {
-                            eprintln!("{} : Exiting", err);
-                            exit(-1);
-                        }
-                    }
-                    let _ = f.write(buf);
+            Instr::Sys(call) => {
+                self.syscall(call)?;
+                if self.exit.is_some() {
+                    next_addr = program.program.len();
                 }
-                _ => todo!(),
-            },
+            }
             Instr::LetBind(v) => {
                 for _ in 0..v {
-                    let value = stack.pop();
-                    rstack.push(value);
+                    let value = self.pop()?;
+                    self.locals
+                        .push(value)
+                        .ok_or(TrapKind::ReturnStackOverflow)?;
                 }
             }
-            Instr::PushBind(v) => {
-                let value = rstack.get(rstack.len() - 1 - v);
-                stack.push(value);
+            Instr::PushBind(slot) => {
+                let depth = self.locals.depth();
+                let value = self
+                    .frame
+                    .checked_add(slot)
+                    .filter(|index| *index < depth)
+                    .and_then(|index| self.locals.peek(depth - index))
+                    .ok_or(TrapKind::ReturnStackUnderflow)?;
+                self.push(value)?;
             }
             Instr::UnBind(v) => {
-                for _ in 0..v {
-                    rstack.pop();
+                let depth = self.locals.depth();
+                if self
+                    .frame
+                    .checked_add(v)
+                    .is_none_or(|needed| depth < needed)
+                {
+                    return Err(TrapKind::ReturnStackUnderflow);
                 }
+                self.locals.truncate(self.locals.depth() - v);
             }
-            Instr::PushI32(v) => stack.push(v as u64),
-            Instr::PushPtr(v) => stack.push(v as u64),
+            Instr::PushI32(v) => self.push(v as u64)?,
+            Instr::PushPtr(v) => self.push(v as u64)?,
             Instr::Drop => {
-                stack.pop();
+                self.pop()?;
             }
             Instr::Dup => {
-                let a = stack.pop();
-                stack.push(a);
-                stack.push(a);
+                let a = self.pop()?;
+                self.push(a)?;
+                self.push(a)?;
             }
             Instr::Swap => {
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push(b);
-                stack.push(a);
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
             }
             Instr::Over => {
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push(a);
-                stack.push(b);
-                stack.push(a);
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a)?;
+                self.push(b)?;
+                self.push(a)?;
             }
             Instr::Rot => {
-                let c = stack.pop();
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push(b);
-                stack.push(c);
-                stack.push(a);
+                let c = self.pop()?;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b)?;
+                self.push(c)?;
+                self.push(a)?;
             }
             Instr::Write(bytes) => {
-                let b = stack.pop() as usize; // ptr
-                let a = stack.pop(); // value
+                let b = self.pop()?; // ptr
+                let a = self.pop()?; // value
                 match bytes {
-                    64 => mem.write(b, a as u64),
-                    32 => mem.write(b, a as u32),
-                    16 => mem.write(b, a as u16),
-                    08 => mem.write(b, a as u8),
-                    _ => todo!(),
+                    64 => self.write(b, a)?,
+                    32 => self.write(b, a as u32)?,
+                    16 => self.write(b, a as u16)?,
+                    8 => self.write(b, a as u8)?,
+                    _ => return Err(TrapKind::InvalidWidth(bytes)),
                 }
-                // unsafe {
-                //     match bytes {
-                //         64 => *(a as *mut u64) = b,
-                //         32 => *(a as *mut u32) = b as u32,
-                //         16 => *(a as *mut u16) = b as u16,
-                //         8 => *(a as *mut u8) = b as u8,
-                //         _ => todo!(),
-                //     }
-                // }
             }
             Instr::Read(bytes) => {
-                let a = stack.pop() as usize; // ptr
+                let a = self.pop()?; // ptr
                 let value = match bytes {
-                    64 => mem.read::<u64>(a) as u64,
-                    32 => mem.read::<u32>(a) as u64,
-                    16 => mem.read::<u16>(a) as u64,
-                    08 => mem.read::<u8>(a) as u64,
-                    _ => todo!(),
+                    64 => self.read::<u64>(a)?,
+                    32 => self.read::<u32>(a)? as u64,
+                    16 => self.read::<u16>(a)? as u64,
+                    8 => self.read::<u8>(a)? as u64,
+                    _ => return Err(TrapKind::InvalidWidth(bytes)),
                 };
-                stack.push(value);
-
-                // unsafe {
-                //     let value = match bytes {
-                //         64 => *(a as *mut u64) as u64,
-                //         32 => *(a as *mut u32) as u64,
-                //         16 => *(a as *mut u16) as u64,
-                //         8 => *(a as *mut u8) as u64,
-                //         _ => todo!(),
-                //     };
-                //     stack.push(value);
-                // }
+                self.push(value)?;
             }
             Instr::Debug => {
-                println!("Debug:\nData Stack: {}", stack);
+                let dump = format!("Debug:\nData Stack: {}\n", self.stack);
+                // Like `println!`, debug output is dropped when stdout is closed.
+                let _ = self.fds.write(1, dump.as_bytes());
             }
             Instr::Jmp(rel_addr) => {
                 next_addr = jump(ip, rel_addr);
             }
             Instr::JmpIf(rel_addr) => {
-                let test = stack.pop();
+                let test = self.pop()?;
                 if test == 0 {
                     next_addr = jump(ip, rel_addr);
                 }
@@ -214,59 +503,453 @@ pub fn vm_run(program: Bytecode) {
                 next_addr = abs_addr;
             }
             Instr::AJmpIf(abs_addr) => {
-                let test = stack.pop();
+                let test = self.pop()?;
                 if test == 0 {
                     next_addr = abs_addr;
                 }
             }
-            Instr::PlusI => {
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push(a + b);
-            }
-            Instr::MultI => {
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push(a * b);
-            }
-            Instr::Offset => {
-                let b = stack.pop(); // offset
-                let a = stack.pop(); // ptr
-                stack.push(a + b);
-            }
-            Instr::Mod => {
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push(a % b);
-            }
-            Instr::EqI => {
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push((a == b) as u64);
-            }
-            Instr::NEqI => {
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push((a != b) as u64);
-            }
-            Instr::Lt => {
-                let b = stack.pop();
-                let a = stack.pop();
-                stack.push((a < b) as u64);
+            Instr::DivI | Instr::DivS | Instr::Mod | Instr::ModS => {
+                self.divop(binary(&program.program[ip]).expect("divides"))?
+            }
+            Instr::PlusI
+            | Instr::MinusI
+            | Instr::MultI
+            | Instr::Offset
+            | Instr::EqI
+            | Instr::NEqI
+            | Instr::Lt
+            | Instr::Gt
+            | Instr::Le
+            | Instr::Ge
+            | Instr::LtS
+            | Instr::GtS
+            | Instr::LeS
+            | Instr::GeS
+            | Instr::BitAnd
+            | Instr::BitOr
+            | Instr::BitXor
+            | Instr::Shl
+            | Instr::Shr
+            | Instr::Sar => self.binop(binary(&program.program[ip]).expect("takes 2 values"))?,
+            Instr::BitNot | Instr::Not | Instr::Trunc(_) | Instr::SignExt(_) => {
+                let instr = &program.program[ip];
+                self.unop(|a| unary(instr, a).expect("takes 1 value"))?
             }
             Instr::Bind(rel) => {
-                let rel = rel as usize * size_of::<Value>();
-                assert!(stack.len() >= rel && rel <= stack.top);
-                stack.push(stack.data.read((stack.top + rel) - size_of::<Value>()));
+                let value = self
+                    .stack
+                    .peek(rel as usize)
+                    .ok_or(TrapKind::StackUnderflow)?;
+                self.push(value)?;
             }
             Instr::Ret => {
-                next_addr = rstack.pop() as usize;
+                next_addr = self.leave()?;
             }
             Instr::Call(addr) => {
-                rstack.push(next_addr as u64);
+                self.enter(next_addr)?;
                 next_addr = addr;
             }
+            Instr::Native(index) => self.call_native(&program.natives[index])?,
+        }
+        Ok(next_addr)
+    }
+}
+
+/// A program loaded to run, or to be called into by the host.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use chs_parser::{parse_program, DataType, Signature};
+/// use chs_vm_v2::{compiler::compile_with, natives::Natives, Vm};
+///
+/// let mut natives = Natives::default();
+/// let sig = Signature::new(&[DataType::Int], &[DataType::Int]);
+/// natives.register("double", sig, |args| Ok(vec![args[0] * 2]));
+///
+/// let source = "fn quad : int -> int { double double }";
+/// let mut program = parse_program(source, "quad.chs").unwrap();
+/// type_check::check_program_with(&mut program, &natives.signatures()).unwrap();
+/// let bytecode = compile_with(&program, &natives.signatures());
+///
+/// let mut vm = Vm::with_natives(bytecode, natives);
+/// vm.run().unwrap();
+/// assert_eq!(vm.call("quad", &[5]).unwrap(), [20]);
+/// ```
+pub struct Vm {
+    program: Bytecode,
+    machine: Machine,
+    /// The instruction `step` runs next.
+    ip: usize,
+    phase: Phase,
+    tracer: Option<Box<dyn Tracer>>,
+    /// Instructions left to run, if they are limited.
+    fuel: Option<u64>,
+    code: Lowered,
+}
+
+/// The program for the fast loop of `Machine::run`.
+enum Lowered {
+    /// Not lowered since the program or its natives changed.
+    NotYet,
+    Fast(Vec<Op>),
+    /// The program does not pass `verify`, or a trap has left the stacks in
+    /// a state it did not check, so every instruction goes through
+    /// `Machine::step`.
+    Checked,
+}
+
+/// How far `Vm::step` got through the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    TopLevel,
+    /// In `main`, which returns this many values.
+    Main(usize),
+    Done(i32),
+}
+
+impl Vm {
+    pub fn new(program: Bytecode) -> Self {
+        Self::with_natives(program, Natives::default())
+    }
+
+    pub fn with_natives(program: Bytecode, natives: Natives) -> Self {
+        Self::with_limits(program, natives, Limits::default())
+            .expect("the program needs more memory than `format::MAX_MEMORY`")
+    }
+
+    /// Like `with_natives`, with what the program may use. Fails when the
+    /// program needs more memory than `limits` allow or the system has.
+    pub fn with_limits(program: Bytecode, natives: Natives, limits: Limits) -> Result<Self, Trap> {
+        let strs_size: usize = program.strs.iter().map(|s| s.len()).sum();
+        let needed = strs_size.saturating_add(program.program_mem);
+        let limit = limits.memory.unwrap_or(MAX_MEMORY);
+        // Below the limit, the system may still not have that much.
+        let mem = Some(needed)
+            .filter(|needed| *needed <= limit)
+            .and_then(Memory::try_new);
+        let Some(mut mem) = mem else {
+            return Err(Trap {
+                kind: TrapKind::MemoryLimit { needed, limit },
+                ip: program.entry,
+                loc: None,
+                stack: vec![],
+            });
+        };
+        mem.set_write_pos(0);
+        for e in program.strs.iter() {
+            for v in e.iter() {
+                mem.write_push::<u8>(*v)
+            }
+        }
+        let value = size_of::<Value>();
+        let machine = Machine {
+            stack: VMStack::<Value>::new(limits.stack.saturating_mul(value)),
+            rstack: VMStack::<Value>::new(limits.return_stack.saturating_mul(value)),
+            locals: VMStack::<Value>::new(limits.return_stack.saturating_mul(value)),
+            frame: 0,
+            mem,
+            natives,
+            fds: FdTable::default(),
+            policy: Policy::default(),
+            args: vec![],
+            start: Instant::now(),
+            exit: None,
+        };
+        let ip = program.entry;
+        Ok(Self {
+            program,
+            machine,
+            ip,
+            phase: Phase::TopLevel,
+            tracer: None,
+            fuel: limits.fuel,
+            code: Lowered::NotYet,
+        })
+    }
+
+    /// Instructions the program may still run, if they are limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Let the program run `fuel` more instructions, when they are limited.
+    /// A program stopped by `TrapKind::OutOfFuel` goes on from where it
+    /// stopped with the next `run` or `step`.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = self.fuel.as_mut() {
+            *left = left.saturating_add(fuel);
         }
-        ip = next_addr
     }
+
+    pub fn natives_mut(&mut self) -> &mut Natives {
+        // The program is verified again with the new signatures.
+        self.code = Lowered::NotYet;
+        &mut self.machine.natives
+    }
+
+    /// What the file descriptors of the program write to.
+    pub fn fds_mut(&mut self) -> &mut FdTable {
+        &mut self.machine.fds
+    }
+
+    /// Which syscalls the program may make. All of them at first.
+    pub fn policy_mut(&mut self) -> &mut Policy {
+        &mut self.machine.policy
+    }
+
+    /// Arguments the program gets through `$argc` and `$argv`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.machine.args = args;
+    }
+
+    pub fn program(&self) -> &Bytecode {
+        &self.program
+    }
+
+    /// Tell `tracer` about each instruction that runs from now on. Give it
+    /// an `Rc<RefCell<_>>` to look at it afterwards.
+    pub fn set_tracer(&mut self, tracer: impl Tracer + 'static) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Run the top-level code of the program to its end, then its `main` fn
+    /// when it has one.
+    pub fn run(&mut self) -> Result<ExitStatus, Trap> {
+        loop {
+            match self.fast(self.ip) {
+                Ok(ip) => self.ip = ip,
+                Err(trap) => {
+                    self.ip = trap.ip;
+                    return Err(trap);
+                }
+            }
+            if let Some(status) = self.step()? {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Run the next instruction, like `run` does. Once the program has
+    /// ended, returns how, and keeps returning it.
+    ///
+    /// ```
+    /// use chs_parser::parse_program;
+    /// use chs_vm_v2::{compiler::compile, Vm};
+    ///
+    /// let program = parse_program("1 2 + $exit", "add.chs").unwrap();
+    /// let mut vm = Vm::new(compile(&program));
+    /// vm.step().unwrap();
+    /// vm.step().unwrap();
+    /// assert_eq!(vm.stack(), [1, 2]);
+    /// while vm.step().unwrap().is_none() {}
+    /// assert_eq!(vm.step().unwrap().unwrap().code, 3);
+    /// ```
+    pub fn step(&mut self) -> Result<Option<ExitStatus>, Trap> {
+        let ip = self.ip;
+        if ip < self.program.len() {
+            self.ip = self.exec_one(ip).inspect_err(|trap| self.taint(trap))?;
+            return Ok(None);
+        }
+        let code = match (self.phase, self.machine.exit.take()) {
+            (Phase::Done(code), _) | (_, Some(code)) => code,
+            (Phase::TopLevel, None) => match self.program.exports.get("main") {
+                Some(main) => {
+                    self.ip = main.addr;
+                    if let Some(tracer) = self.tracer.as_mut() {
+                        tracer.enter(&self.program, main.addr);
+                    }
+                    self.phase = Phase::Main(main.signature.outs.len());
+                    // Returning to the end of the program ends `main`.
+                    let end = self.program.len();
+                    self.machine
+                        .enter(end)
+                        .map_err(|kind| self.trap(kind, ip))?;
+                    return Ok(None);
+                }
+                None => 0,
+            },
+            (Phase::Main(outs), None) => {
+                // The first value `main` returns, which is the deepest.
+                let mut code = 0;
+                for _ in 0..outs {
+                    code = self.machine.pop().map_err(|kind| self.trap(kind, ip))? as i32;
+                }
+                code
+            }
+        };
+        self.phase = Phase::Done(code);
+        Ok(Some(ExitStatus { code }))
+    }
+
+    /// The instruction `step` runs next, which is the length of the program
+    /// once it has ended.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Call the function `name` of the program with `args`, the deepest
+    /// first, and return what it leaves on the stack in the same order. The
+    /// stacks are put back as they were when it traps or exits.
+    pub fn call(&mut self, name: &str, args: &[u64]) -> Result<Vec<u64>, CallError> {
+        let export = match self.program.exports.get(name) {
+            Some(export) => export.clone(),
+            None => return Err(CallError::UnknownFunction(name.to_string())),
+        };
+        let expected = export.signature.ins.len();
+        if args.len() != expected {
+            return Err(CallError::Arity {
+                expected,
+                got: args.len(),
+            });
+        }
+        let depth = self.machine.stack.depth();
+        let rdepth = self.machine.rstack.depth();
+        let locals = self.machine.locals.depth();
+        let frame = self.machine.frame;
+        let result = match self.call_at(export.addr, args, export.signature.outs.len()) {
+            Ok(_) if self.machine.exit.is_some() => {
+                Err(CallError::Exited(self.machine.exit.take().unwrap_or(0)))
+            }
+            Ok(results) => Ok(results),
+            Err(trap) => Err(CallError::Trap(trap)),
+        };
+        if result.is_err() {
+            self.machine.stack.truncate(depth);
+            self.machine.rstack.truncate(rdepth);
+            self.machine.locals.truncate(locals);
+            self.machine.frame = frame;
+        }
+        result
+    }
+
+    fn call_at(&mut self, addr: usize, args: &[u64], outs: usize) -> Result<Vec<u64>, Trap> {
+        for arg in args {
+            self.machine
+                .push(*arg)
+                .map_err(|kind| self.trap(kind, addr))?;
+        }
+        // Returning to the end of the program stops it.
+        self.machine
+            .enter(self.program.len())
+            .map_err(|kind| self.trap(kind, addr))?;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.enter(&self.program, addr);
+        }
+        let ip = self.fast(addr)?;
+        self.exec(ip)?;
+        if self.machine.exit.is_some() {
+            return Ok(vec![]);
+        }
+        let mut results = vec![0; outs];
+        for result in results.iter_mut().rev() {
+            *result = self.machine.pop().map_err(|kind| self.trap(kind, addr))?;
+        }
+        Ok(results)
+    }
+
+    /// Values on the data stack, bottom first.
+    pub fn stack(&self) -> Vec<u64> {
+        self.machine.stack.values()
+    }
+
+    /// Values on the return stack, bottom first: for each call, the address
+    /// it returns to and where the binds of its caller start in `locals`.
+    pub fn return_stack(&self) -> Vec<u64> {
+        self.machine.rstack.values()
+    }
+
+    /// Values of the let binds of all calls, bottom first.
+    pub fn locals(&self) -> Vec<u64> {
+        self.machine.locals.values()
+    }
+
+    /// The memory of the program: its strings, then what it allocates.
+    pub fn memory(&self) -> &[u8] {
+        let mem = &self.machine.mem;
+        unsafe { &*slice_from_raw_parts(mem.inner, mem.size()) }
+    }
+
+    /// Run from `ip` with the fast loop, when the program passes `verify`
+    /// and no tracer is set, and return where it stopped. Otherwise returns
+    /// `ip` for `exec_one` to go on from.
+    fn fast(&mut self, ip: usize) -> Result<usize, Trap> {
+        if self.tracer.is_some() {
+            return Ok(ip);
+        }
+        if let Lowered::NotYet = self.code {
+            self.code = match fast::lower(&self.program, &self.machine.natives.signatures()) {
+                Some(code) => Lowered::Fast(code),
+                None => Lowered::Checked,
+            };
+        }
+        let Lowered::Fast(code) = &self.code else {
+            return Ok(ip);
+        };
+        match self.machine.run(&self.program, code, ip, &mut self.fuel) {
+            Ok(ip) => Ok(ip),
+            Err((kind, ip)) => {
+                let trap = self.trap(kind, ip);
+                self.taint(&trap);
+                Err(trap)
+            }
+        }
+    }
+
+    /// After `trap`, the stacks may not be what `verify` expects at the
+    /// instruction to run next, so leave the rest to the checked path. Only
+    /// running out of fuel stops before an instruction changes anything.
+    fn taint(&mut self, trap: &Trap) {
+        if trap.kind != TrapKind::OutOfFuel {
+            self.code = Lowered::Checked;
+        }
+    }
+
+    fn exec(&mut self, mut ip: usize) -> Result<(), Trap> {
+        while ip < self.program.len() {
+            ip = self.exec_one(ip)?;
+        }
+        Ok(())
+    }
+
+    /// Run the instruction at `ip` and return the next one.
+    fn exec_one(&mut self, ip: usize) -> Result<usize, Trap> {
+        if let Some(fuel) = self.fuel.as_mut() {
+            if *fuel == 0 {
+                return Err(self.trap(TrapKind::OutOfFuel, ip));
+            }
+            *fuel -= 1;
+        }
+        let Some(tracer) = self.tracer.as_mut() else {
+            return self
+                .machine
+                .step(&self.program, ip)
+                .map_err(|kind| self.trap(kind, ip));
+        };
+        tracer.instr(&self.program, ip, &self.machine.stack.values());
+        let next = match self.machine.step(&self.program, ip) {
+            Ok(next) => next,
+            Err(kind) => return Err(self.trap(kind, ip)),
+        };
+        let tracer = self.tracer.as_mut().expect("checked above");
+        match self.program.program[ip] {
+            Instr::Call(addr) => tracer.enter(&self.program, addr),
+            Instr::Ret => tracer.leave(),
+            _ => {}
+        }
+        Ok(next)
+    }
+
+    fn trap(&self, kind: TrapKind, ip: usize) -> Trap {
+        Trap {
+            kind,
+            ip,
+            loc: self.program.loc(ip),
+            stack: self.machine.stack.values(),
+        }
+    }
+}
+
+/// Run `program` to its end, or until an instruction fails.
+pub fn vm_run(program: Bytecode) -> Result<ExitStatus, Trap> {
+    Vm::new(program).run()
 }
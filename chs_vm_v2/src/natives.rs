@@ -0,0 +1,53 @@
+//! Functions of the host that chs programs call as words.
+use std::{collections::HashMap, fmt};
+
+use chs_parser::Signature;
+
+/// Takes the arguments of the call, the deepest first, and returns the values
+/// to push, or a message that stops the program.
+pub type NativeFn = Box<dyn FnMut(&[u64]) -> Result<Vec<u64>, String>>;
+
+pub struct Native {
+    pub signature: Signature,
+    pub(crate) func: NativeFn,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Native")
+            .field("signature", &self.signature)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Host functions by name. The same signatures are given to
+/// `type_check::check_program_with` and `compiler::compile_with` so that the
+/// program can use them.
+#[derive(Debug, Default)]
+pub struct Natives {
+    fns: HashMap<String, Native>,
+}
+
+impl Natives {
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        signature: Signature,
+        func: impl FnMut(&[u64]) -> Result<Vec<u64>, String> + 'static,
+    ) {
+        let native = Native {
+            signature,
+            func: Box::new(func),
+        };
+        self.fns.insert(name.into(), native);
+    }
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Native> {
+        self.fns.get_mut(name)
+    }
+    pub fn signatures(&self) -> HashMap<String, Signature> {
+        self.fns
+            .iter()
+            .map(|(name, native)| (name.clone(), native.signature.clone()))
+            .collect()
+    }
+}
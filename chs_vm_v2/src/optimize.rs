@@ -0,0 +1,313 @@
+//! Rewrites of `Bytecode` that run the same program with fewer instructions.
+//!
+//! The compiler emits code as it is written, so `8 8 +` is three
+//! instructions and every `if` in a loop jumps to the jump back. `optimize`
+//! replaces these with what they compute. Every instruction it keeps keeps
+//! its source location, so errors are reported where they were before.
+//!
+//! ```
+//! use chs_parser::parse_program;
+//! use chs_vm_v2::{compiler::compile, instructions::Instr, optimize::optimize};
+//!
+//! let source = "1 8 8 + dup drop swap swap debug drop drop";
+//! let program = optimize(&compile(&parse_program(source, "add.chs").unwrap()), 1);
+//! assert_eq!(
+//!     program.program,
+//!     [Instr::PushI32(1), Instr::PushI32(16), Instr::Debug, Instr::Drop, Instr::Drop]
+//! );
+//! ```
+use std::collections::HashSet;
+
+use crate::{
+    arith::{binary, unary},
+    instructions::{Bytecode, Instr},
+    jump, jump_to,
+};
+
+/// Levels of `optimize`, like `-O` of `chsi`.
+pub const MAX_LEVEL: u8 = 2;
+
+/// Optimize `program` by `level`:
+///
+/// - 0 leaves it as it is.
+/// - 1 folds operations on constants and removes stack shuffles that undo
+///   each other, like `dup drop` and `swap swap`.
+/// - 2 also folds conditions that are constant, makes jumps to jumps go to
+///   where the last one goes and removes the code that cannot be reached.
+///
+/// Levels above 2 are the same as 2. `program` is expected to pass `verify`.
+pub fn optimize(program: &Bytecode, level: u8) -> Bytecode {
+    if level == 0 {
+        return program.clone();
+    }
+    let mut opt = Optimizer {
+        code: program.program.iter().cloned().map(Some).collect(),
+        program,
+    };
+    // Each rewrite can make room for another, so go on until none applies.
+    loop {
+        let mut changed = opt.peephole(level);
+        if level >= 2 {
+            changed |= opt.thread_jumps();
+            changed |= opt.remove_dead();
+        }
+        if !changed {
+            break;
+        }
+    }
+    opt.finish()
+}
+
+/// The instructions of a program while it is optimized. Removed ones are
+/// `None`, so that addresses stay the same until `finish`.
+struct Optimizer<'a> {
+    code: Vec<Option<Instr>>,
+    program: &'a Bytecode,
+}
+
+impl Optimizer<'_> {
+    /// The first instruction at or after `addr` that was not removed, or the
+    /// end of the program. This is where a jump to `addr` goes.
+    fn live(&self, addr: usize) -> usize {
+        (addr..self.code.len())
+            .find(|&ip| self.code[ip].is_some())
+            .unwrap_or(self.code.len())
+    }
+
+    /// Where the jump or call at `ip` goes, if it is one.
+    fn target(&self, ip: usize) -> Option<usize> {
+        match *self.code[ip].as_ref()? {
+            Instr::Jmp(rel) | Instr::JmpIf(rel) => Some(jump(ip, rel)),
+            Instr::AJmp(addr) | Instr::AJmpIf(addr) | Instr::Call(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Addresses that code runs from other than the one before: the entry,
+    /// the fns and where jumps go.
+    fn entries(&self) -> HashSet<usize> {
+        let mut entries: HashSet<usize> = (0..self.code.len())
+            .filter_map(|ip| self.target(ip))
+            .chain(self.program.exports.values().map(|export| export.addr))
+            .chain([self.program.entry])
+            .map(|addr| self.live(addr))
+            .collect();
+        entries.insert(self.code.len());
+        entries
+    }
+
+    /// Rewrite runs of instructions that only the first of is run from
+    /// elsewhere, so that they can be replaced as a whole.
+    fn peephole(&mut self, level: u8) -> bool {
+        let entries = self.entries();
+        let mut changed = false;
+        let mut ip = self.live(0);
+        while ip < self.code.len() {
+            let second = self.live(ip + 1);
+            let third = self.live(second + 1);
+            let get = |at: usize| match self.code.get(at) {
+                Some(Some(instr)) if !entries.contains(&at) => Some(instr.clone()),
+                _ => None,
+            };
+            let (first, next, last) = (self.code[ip].clone(), get(second), get(third));
+            let rewrite = match (first, next, last) {
+                (Some(Instr::PushI32(a)), Some(Instr::PushI32(b)), Some(op)) => {
+                    fold_binary(&op, a, b).map(|value| (vec![Instr::PushI32(value)], 3))
+                }
+                (Some(Instr::PushI32(a)), Some(op), _) if unary(&op, 0).is_some() => {
+                    fold_unary(&op, a).map(|value| (vec![Instr::PushI32(value)], 2))
+                }
+                (Some(Instr::PushI32(a)), Some(op), _) if identity(&op, a) => Some((vec![], 2)),
+                (
+                    Some(Instr::Dup | Instr::PushI32(_) | Instr::PushPtr(_)),
+                    Some(Instr::Drop),
+                    _,
+                ) => Some((vec![], 2)),
+                (Some(Instr::Swap), Some(Instr::Swap), _) => Some((vec![], 2)),
+                (Some(Instr::PushI32(c)), Some(Instr::JmpIf(rel)), _) if level >= 2 => {
+                    // `JmpIf` jumps when the value is zero.
+                    let target = jump(second, rel);
+                    match c {
+                        0 => Some((vec![Instr::Jmp(jump_to(ip, target))], 2)),
+                        _ => Some((vec![], 2)),
+                    }
+                }
+                _ => None,
+            };
+            if let Some((replacement, len)) = rewrite {
+                let run = [ip, second, third];
+                for (i, &at) in run[..len].iter().enumerate() {
+                    self.code[at] = replacement.get(i).cloned();
+                }
+                changed = true;
+            }
+            ip = self.live(ip + 1);
+        }
+        changed
+    }
+
+    /// Make jumps to jumps go to where the last one goes, and remove jumps to
+    /// the next instruction.
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for ip in 0..self.code.len() {
+            let Some(target) = self.target(ip) else {
+                continue;
+            };
+            if matches!(self.code[ip], Some(Instr::Call(_))) {
+                continue;
+            }
+            let mut end = self.live(target);
+            let mut seen = HashSet::new();
+            while let Some(Some(Instr::Jmp(_) | Instr::AJmp(_))) = self.code.get(end) {
+                if !seen.insert(end) {
+                    break;
+                }
+                end = self.live(self.target(end).expect("is a jump"));
+            }
+            // A loop of jumps never gets anywhere, so leave it as it is.
+            if seen.contains(&end) {
+                continue;
+            }
+            let unconditional = matches!(self.code[ip], Some(Instr::Jmp(_) | Instr::AJmp(_)));
+            let replacement = if end == self.live(ip + 1) {
+                // A conditional jump to the next instruction still takes the
+                // condition.
+                (!unconditional).then_some(Instr::Drop)
+            } else if unconditional && matches!(self.code.get(end), Some(Some(Instr::Ret))) {
+                Some(Instr::Ret)
+            } else if unconditional && matches!(self.code.get(end), Some(Some(Instr::Halt))) {
+                Some(Instr::Halt)
+            } else if end != target {
+                Some(match self.code[ip] {
+                    Some(Instr::Jmp(_)) => Instr::Jmp(jump_to(ip, end)),
+                    Some(Instr::JmpIf(_)) => Instr::JmpIf(jump_to(ip, end)),
+                    Some(Instr::AJmp(_)) => Instr::AJmp(end),
+                    _ => Instr::AJmpIf(end),
+                })
+            } else {
+                continue;
+            };
+            self.code[ip] = replacement;
+            changed = true;
+        }
+        changed
+    }
+
+    /// Remove the instructions that no path from the entry or from a fn
+    /// reaches.
+    fn remove_dead(&mut self) -> bool {
+        let len = self.code.len();
+        let mut reached = vec![false; len];
+        let mut work: Vec<usize> = self
+            .program
+            .exports
+            .values()
+            .map(|export| export.addr)
+            .chain([self.program.entry])
+            .collect();
+        while let Some(addr) = work.pop() {
+            let ip = self.live(addr);
+            if ip >= len || reached[ip] {
+                continue;
+            }
+            reached[ip] = true;
+            let next = ip + 1;
+            match self.code[ip] {
+                Some(Instr::Halt | Instr::Ret) => {}
+                Some(Instr::Jmp(_) | Instr::AJmp(_)) => work.extend(self.target(ip)),
+                Some(Instr::JmpIf(_) | Instr::AJmpIf(_) | Instr::Call(_)) => {
+                    work.push(next);
+                    work.extend(self.target(ip));
+                }
+                _ => work.push(next),
+            }
+        }
+        let mut changed = false;
+        for (slot, reached) in self.code.iter_mut().zip(reached) {
+            if slot.is_some() && !reached {
+                *slot = None;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// The program without the removed instructions, with every address
+    /// moved to match.
+    fn finish(self) -> Bytecode {
+        // The new address of each instruction is the number kept before it.
+        // A removed one gets the address of the next one kept.
+        let mut addrs = Vec::with_capacity(self.code.len() + 1);
+        let mut kept = 0;
+        for slot in &self.code {
+            addrs.push(kept);
+            kept += slot.is_some() as usize;
+        }
+        addrs.push(kept);
+        let mut program = Vec::with_capacity(kept);
+        let mut locs = Vec::new();
+        for (ip, slot) in self.code.iter().enumerate() {
+            let Some(instr) = slot else {
+                continue;
+            };
+            let at = addrs[ip];
+            program.push(match *instr {
+                Instr::Jmp(rel) => Instr::Jmp(jump_to(at, addrs[jump(ip, rel)])),
+                Instr::JmpIf(rel) => Instr::JmpIf(jump_to(at, addrs[jump(ip, rel)])),
+                Instr::AJmp(addr) => Instr::AJmp(addrs[addr]),
+                Instr::AJmpIf(addr) => Instr::AJmpIf(addrs[addr]),
+                Instr::Call(addr) => Instr::Call(addrs[addr]),
+                ref instr => instr.clone(),
+            });
+            if let Some(loc) = self.program.loc(ip) {
+                locs.push(loc);
+            }
+        }
+        let mut bytecode = self.program.clone();
+        bytecode.program = program;
+        // Locations are all there or not at all.
+        bytecode.locs = if locs.len() == kept { locs } else { vec![] };
+        bytecode.entry = addrs[bytecode.entry];
+        for export in bytecode.exports.values_mut() {
+            export.addr = addrs[export.addr];
+        }
+        bytecode
+    }
+}
+
+/// `a b op` as one constant, when `op` takes two values and the result fits
+/// in a `PushI32`. Division by zero is left to stop the program.
+fn fold_binary(op: &Instr, a: i32, b: i32) -> Option<i32> {
+    let divides = matches!(op, Instr::DivI | Instr::DivS | Instr::Mod | Instr::ModS);
+    if divides && b == 0 {
+        return None;
+    }
+    let value = binary(op)?(a as i64 as u64, b as i64 as u64);
+    i32::try_from(value as i64).ok()
+}
+
+/// `a op` as one constant, when the result fits in a `PushI32`.
+fn fold_unary(op: &Instr, a: i32) -> Option<i32> {
+    let value = unary(op, a as i64 as u64)?;
+    i32::try_from(value as i64).ok()
+}
+
+/// Whether `x a op` leaves `x` as it is.
+fn identity(op: &Instr, a: i32) -> bool {
+    match a {
+        0 => matches!(
+            op,
+            Instr::PlusI
+                | Instr::MinusI
+                | Instr::Offset
+                | Instr::BitOr
+                | Instr::BitXor
+                | Instr::Shl
+                | Instr::Shr
+                | Instr::Sar
+        ),
+        1 => matches!(op, Instr::MultI | Instr::DivI | Instr::DivS),
+        _ => false,
+    }
+}
@@ -0,0 +1,184 @@
+//! What the VM does for each `Syscall`, and which of them a program may make.
+use std::{collections::HashSet, env, fs::OpenOptions};
+
+use chs_parser::{Syscall, OPEN_APPEND, OPEN_CREATE, OPEN_READ, OPEN_TRUNCATE, OPEN_WRITE};
+
+use crate::{io::Stream, Machine, TrapKind, Value};
+
+/// The syscalls a program may make. Making any other one traps.
+///
+/// ```
+/// use chs_parser::{parse_program, Syscall};
+/// use chs_vm_v2::{compiler::compile, sys::Policy, TrapKind, Vm};
+///
+/// let mut policy = Policy::deny_all();
+/// policy.allow(Syscall::Exit);
+///
+/// let program = parse_program("\"/etc/passwd\" 1 $open $exit", "open.chs").unwrap();
+/// let mut vm = Vm::new(compile(&program));
+/// *vm.policy_mut() = policy;
+/// let trap = vm.run().unwrap_err();
+/// assert_eq!(trap.kind, TrapKind::Denied(Syscall::Open));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    allowed: HashSet<Syscall>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+impl Policy {
+    pub fn allow_all() -> Self {
+        Self {
+            allowed: Syscall::all().collect(),
+        }
+    }
+    pub fn deny_all() -> Self {
+        Self {
+            allowed: HashSet::default(),
+        }
+    }
+    pub fn allow(&mut self, call: Syscall) {
+        self.allowed.insert(call);
+    }
+    pub fn deny(&mut self, call: Syscall) {
+        self.allowed.remove(&call);
+    }
+    pub fn allows(&self, call: Syscall) -> bool {
+        self.allowed.contains(&call)
+    }
+}
+
+/// Result of the calls that can fail without stopping the program.
+const FAILED: Value = -1i64 as Value;
+
+impl Machine {
+    pub(crate) fn syscall(&mut self, call: Syscall) -> Result<(), TrapKind> {
+        if !self.policy.allows(call) {
+            return Err(TrapKind::Denied(call));
+        }
+        match call {
+            Syscall::Write => {
+                let fd = self.pop()?;
+                let ptr = self.pop()?;
+                let len = self.pop()? as usize;
+                let buf = self.bytes(ptr, len)?;
+                self.fds.write(fd, buf).ok_or(TrapKind::BadFd(fd as i64))?;
+            }
+            Syscall::Read => {
+                let fd = self.pop()?;
+                let ptr = self.pop()?;
+                let len = self.pop()? as usize;
+                self.bounds(ptr, len)?;
+                let mut buf = vec![0; len];
+                let read = self
+                    .fds
+                    .read(fd, &mut buf)
+                    .ok_or(TrapKind::BadFd(fd as i64))?;
+                match read {
+                    Ok(n) => {
+                        self.copy_out(ptr, n, &buf)?;
+                        self.push(n as Value)?;
+                    }
+                    Err(_) => self.push(FAILED)?,
+                }
+            }
+            Syscall::Open => {
+                let flags = self.pop()? as i64;
+                let ptr = self.pop()?;
+                let len = self.pop()? as usize;
+                let path = String::from_utf8(self.bytes(ptr, len)?.to_vec());
+                let file = path
+                    .ok()
+                    .and_then(|path| open_options(flags).open(path).ok());
+                match file {
+                    Some(file) => {
+                        let fd = self.fds.open(Stream::file(file));
+                        self.push(fd as Value)?;
+                    }
+                    None => self.push(FAILED)?,
+                }
+            }
+            Syscall::Close => {
+                let fd = self.pop()?;
+                let closed = usize::try_from(fd).is_ok_and(|fd| self.fds.close(fd));
+                self.push(if closed { 0 } else { FAILED })?;
+            }
+            Syscall::Exit => {
+                let code = self.pop()?;
+                self.exit = Some(code as i32);
+            }
+            Syscall::Argc => self.push(self.args.len() as Value)?,
+            Syscall::Argv => {
+                let index = self.pop()?;
+                let ptr = self.pop()?;
+                let len = self.pop()? as usize;
+                let arg = usize::try_from(index)
+                    .ok()
+                    .and_then(|i| self.args.get(i))
+                    .map(|arg| arg.clone().into_bytes());
+                self.push_copied(ptr, len, arg)?;
+            }
+            Syscall::Getenv => {
+                let buf = self.pop()?;
+                let buflen = self.pop()? as usize;
+                let ptr = self.pop()?;
+                let len = self.pop()? as usize;
+                let name = String::from_utf8(self.bytes(ptr, len)?.to_vec());
+                let value = name
+                    .ok()
+                    .and_then(|name| env::var(name).ok())
+                    .map(String::into_bytes);
+                self.push_copied(buf, buflen, value)?;
+            }
+            Syscall::Clock => {
+                let nanos = self.start.elapsed().as_nanos();
+                self.push(nanos as Value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy up to `len` bytes of `bytes` to `ptr`.
+    fn copy_out(&mut self, ptr: Value, len: usize, bytes: &[u8]) -> Result<(), TrapKind> {
+        let n = len.min(bytes.len());
+        let index = self.bounds(ptr, n)?;
+        for (i, byte) in bytes[..n].iter().enumerate() {
+            self.mem.write(index + i, *byte);
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` out like `copy_out` and push their full length, or -1
+    /// when there are none.
+    fn push_copied(
+        &mut self,
+        ptr: Value,
+        len: usize,
+        bytes: Option<Vec<u8>>,
+    ) -> Result<(), TrapKind> {
+        match bytes {
+            Some(bytes) => {
+                self.copy_out(ptr, len, &bytes)?;
+                self.push(bytes.len() as Value)
+            }
+            None => self.push(FAILED),
+        }
+    }
+}
+
+fn open_options(flags: i64) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    let write = flags & (OPEN_WRITE | OPEN_APPEND) != 0;
+    options
+        .read(flags & OPEN_READ != 0 || !write)
+        .write(flags & OPEN_WRITE != 0)
+        .append(flags & OPEN_APPEND != 0)
+        .create(flags & OPEN_CREATE != 0)
+        .truncate(flags & OPEN_TRUNCATE != 0);
+    options
+}
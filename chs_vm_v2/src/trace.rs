@@ -0,0 +1,193 @@
+//! Hooks to watch a program run, with a per-instruction trace and a profiler
+//! of the instructions run by each fn.
+//!
+//! ```
+//! use std::{cell::RefCell, rc::Rc};
+//! use chs_parser::parse_program;
+//! use chs_vm_v2::{compiler::compile, trace::Profiler, Vm};
+//!
+//! let source = "fn sq : int -> int { dup * } 3 sq sq drop";
+//! let program = parse_program(source, "sq.chs").unwrap();
+//! let profiler = Rc::new(RefCell::new(Profiler::default()));
+//! let mut vm = Vm::new(compile(&program));
+//! vm.set_tracer(profiler.clone());
+//! vm.run().unwrap();
+//! assert!(profiler.borrow().folded().contains("<top>;sq 6\n"));
+//! ```
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::Write,
+    rc::Rc,
+};
+
+use crate::{
+    disasm::{fn_at, instruction},
+    instructions::Bytecode,
+    verify::name,
+};
+
+/// Gets told about every instruction a `Vm` runs. Set with `Vm::set_tracer`.
+pub trait Tracer {
+    /// Before the instruction at `ip` runs, with the data stack bottom first.
+    fn instr(&mut self, program: &Bytecode, ip: usize, stack: &[u64]);
+    /// A fn starting at `addr` was called, by a `Call`, as `main` or by the
+    /// host.
+    fn enter(&mut self, _program: &Bytecode, _addr: usize) {}
+    /// The last fn that was entered returned.
+    fn leave(&mut self) {}
+}
+
+/// So that the host can keep a handle on the tracer it gave to the `Vm`.
+impl<T: Tracer> Tracer for Rc<RefCell<T>> {
+    fn instr(&mut self, program: &Bytecode, ip: usize, stack: &[u64]) {
+        self.borrow_mut().instr(program, ip, stack)
+    }
+    fn enter(&mut self, program: &Bytecode, addr: usize) {
+        self.borrow_mut().enter(program, addr)
+    }
+    fn leave(&mut self) {
+        self.borrow_mut().leave()
+    }
+}
+
+/// Writes each instruction with its address and the data stack before it,
+/// one per line.
+pub struct Trace<W> {
+    out: W,
+}
+
+impl<W: Write> Trace<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> Tracer for Trace<W> {
+    fn instr(&mut self, program: &Bytecode, ip: usize, stack: &[u64]) {
+        let values: String = stack.iter().map(|v| format!(" {} ", *v as i64)).collect();
+        let _ = writeln!(
+            self.out,
+            "  {:>4}  {:<28} [{}]",
+            ip,
+            instruction(program, ip),
+            values
+        );
+    }
+}
+
+/// Instructions run by a fn, and by the fns it called.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FnProfile {
+    pub calls: u64,
+    /// Instructions run while the fn was on the call stack.
+    pub inclusive: u64,
+    /// Instructions of the fn itself.
+    pub exclusive: u64,
+}
+
+/// Name of the code outside of any fn in profiles.
+pub const TOP: &str = "<top>";
+
+/// Counts the instructions run, by opcode and by fn.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    pub total: u64,
+    pub opcodes: BTreeMap<String, u64>,
+    pub fns: BTreeMap<String, FnProfile>,
+    /// Instructions run with each call stack, its fns joined by `;`.
+    stacks: BTreeMap<String, u64>,
+    /// Names of the fns being run, outermost first.
+    calls: Vec<String>,
+    /// The `calls` joined by `;`.
+    path: String,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self {
+            total: 0,
+            opcodes: BTreeMap::default(),
+            fns: BTreeMap::default(),
+            stacks: BTreeMap::default(),
+            calls: vec![TOP.to_string()],
+            path: TOP.to_string(),
+        }
+    }
+}
+
+impl Tracer for Profiler {
+    fn instr(&mut self, program: &Bytecode, ip: usize, _stack: &[u64]) {
+        self.total += 1;
+        *self.opcodes.entry(name(&program.program[ip])).or_default() += 1;
+        *self.stacks.entry(self.path.clone()).or_default() += 1;
+        // A fn that calls itself still runs each instruction once.
+        let running: BTreeSet<&String> = self.calls.iter().collect();
+        for name in running {
+            self.fns.entry(name.clone()).or_default().inclusive += 1;
+        }
+        let current = self.calls.last().expect("`<top>` is never left");
+        self.fns.entry(current.clone()).or_default().exclusive += 1;
+    }
+
+    fn enter(&mut self, program: &Bytecode, addr: usize) {
+        let name = match fn_at(program, addr) {
+            Some(name) => name.to_string(),
+            None => format!("fn@{}", addr),
+        };
+        self.fns.entry(name.clone()).or_default().calls += 1;
+        self.path.push(';');
+        self.path.push_str(&name);
+        self.calls.push(name);
+    }
+
+    fn leave(&mut self) {
+        if self.calls.len() > 1 {
+            let name = self.calls.pop().expect("checked above");
+            self.path.truncate(self.path.len() - name.len() - 1);
+        }
+    }
+}
+
+impl Profiler {
+    /// The counts by opcode and by fn, the largest first.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Instructions: {}", self.total);
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by_key(|(_, count)| Reverse(**count));
+        let _ = writeln!(out, "By instruction:");
+        for (name, count) in opcodes {
+            let _ = writeln!(out, "  {:>10}  {}", count, name);
+        }
+        let mut fns: Vec<_> = self.fns.iter().collect();
+        fns.sort_by_key(|(_, fn_profile)| Reverse(fn_profile.inclusive));
+        let _ = writeln!(out, "By fn:");
+        let _ = writeln!(
+            out,
+            "  {:>10}  {:>10}  {:>10}  fn",
+            "calls", "inclusive", "exclusive"
+        );
+        for (name, fn_profile) in fns {
+            let _ = writeln!(
+                out,
+                "  {:>10}  {:>10}  {:>10}  {}",
+                fn_profile.calls, fn_profile.inclusive, fn_profile.exclusive, name
+            );
+        }
+        out
+    }
+
+    /// One line per call stack with the number of instructions run in it,
+    /// like `<top>;main;sq 12`. This is the folded format of flamegraph
+    /// tools.
+    pub fn folded(&self) -> String {
+        let mut out = String::new();
+        for (stack, count) in &self.stacks {
+            let _ = writeln!(out, "{} {}", stack, count);
+        }
+        out
+    }
+}
@@ -0,0 +1,273 @@
+//! Checks on `Bytecode` before it runs, for programs that did not come from
+//! the compiler, like the ones loaded from a file.
+//!
+//! Every path through the top-level code and through each exported fn is
+//! followed, with the depth of the data stack and the number of let binds
+//! at each instruction. The program is accepted when every jump lands inside
+//! it, every `Call` is to the start of a fn, no instruction takes more values
+//! than there are and each instruction is reached with the same depths along
+//! every path.
+//!
+//! ```
+//! use chs_vm_v2::{instructions::{Bytecode, Instr}, verify::verify};
+//!
+//! let program = Bytecode::new(vec![Instr::PushI32(1), Instr::PlusI], 0);
+//! let err = verify(&program).unwrap_err();
+//! assert_eq!(err.ip, 1);
+//! assert_eq!(err.message, "`PlusI` takes 2 values but the stack has 1");
+//! ```
+use std::{collections::HashMap, fmt};
+
+use chs_parser::{Loc, Signature};
+
+use crate::{
+    instructions::{Bytecode, Instr},
+    jump,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// The instruction that is wrong.
+    pub ip: usize,
+    /// Its source location, if the bytecode has it.
+    pub loc: Option<Loc>,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at instruction {}", self.message, self.ip)
+    }
+}
+
+pub fn verify(program: &Bytecode) -> Result<(), VerifyError> {
+    verify_with(program, &HashMap::default())
+}
+
+/// Like `verify`, with the signatures of the host functions the program
+/// calls.
+pub fn verify_with(
+    program: &Bytecode,
+    natives: &HashMap<String, Signature>,
+) -> Result<(), VerifyError> {
+    let mut v = Verifier {
+        program,
+        natives,
+        fns: program
+            .exports
+            .values()
+            .map(|export| (export.addr, &export.signature))
+            .collect(),
+        depths: vec![None; program.len()],
+    };
+    v.region(program.entry, 0, None)?;
+    let mut fns: Vec<_> = v.fns.iter().map(|(addr, sig)| (*addr, *sig)).collect();
+    fns.sort_by_key(|(addr, _)| *addr);
+    for (addr, signature) in fns {
+        v.region(addr, signature.ins.len(), Some(signature.outs.len()))?;
+    }
+    Ok(())
+}
+
+struct Verifier<'a> {
+    program: &'a Bytecode,
+    natives: &'a HashMap<String, Signature>,
+    /// Signatures of the fns by the address they start at.
+    fns: HashMap<usize, &'a Signature>,
+    /// Depth of the data stack and number of binds at each instruction that
+    /// was reached.
+    depths: Vec<Option<(usize, usize)>>,
+}
+
+impl Verifier<'_> {
+    fn error(&self, ip: usize, message: String) -> VerifyError {
+        VerifyError {
+            ip,
+            loc: self.program.loc(ip),
+            message,
+        }
+    }
+
+    /// Follow every path from `start`, the top-level code when `outs` is
+    /// `None` and a fn returning `outs` values otherwise.
+    fn region(
+        &mut self,
+        start: usize,
+        depth: usize,
+        outs: Option<usize>,
+    ) -> Result<(), VerifyError> {
+        let len = self.program.len();
+        let mut work = vec![(start, depth, 0)];
+        while let Some((ip, depth, binds)) = work.pop() {
+            if ip == len {
+                if outs.is_some() {
+                    return Err(self.error(ip, "Fn runs past the end of the program".into()));
+                }
+                continue;
+            }
+            match self.depths[ip] {
+                Some(seen) if seen == (depth, binds) => continue,
+                Some((seen, seen_binds)) => {
+                    return Err(self.error(
+                        ip,
+                        format!(
+                            "Reached with {} values and {} binds, and before with {} and {}",
+                            depth, binds, seen, seen_binds
+                        ),
+                    ))
+                }
+                None => self.depths[ip] = Some((depth, binds)),
+            }
+            let instr = &self.program.program[ip];
+            let (ins, results) = self.effect(ip, instr)?;
+            if depth < ins {
+                return Err(self.error(
+                    ip,
+                    format!(
+                        "`{}` takes {} values but the stack has {}",
+                        name(instr),
+                        ins,
+                        depth
+                    ),
+                ));
+            }
+            match *instr {
+                // `Bind` copies the `rel`th value from the top, starting at 1.
+                Instr::Bind(rel) if rel == 0 || rel as usize > depth => {
+                    return Err(
+                        self.error(ip, format!("Copies value {} of a stack of {}", rel, depth))
+                    )
+                }
+                Instr::UnBind(n) if n > binds => {
+                    return Err(self.error(ip, format!("Unbinds {} of {} binds", n, binds)))
+                }
+                Instr::PushBind(i) if i >= binds => {
+                    return Err(self.error(ip, format!("Bind {} of {} binds", i, binds)))
+                }
+                _ => {}
+            }
+            let depth = depth - ins + results;
+            let binds = match *instr {
+                Instr::LetBind(n) => binds + n,
+                Instr::UnBind(n) => binds - n,
+                _ => binds,
+            };
+            let next = ip + 1;
+            let targets = match *instr {
+                Instr::Halt => vec![],
+                Instr::Ret => {
+                    let Some(outs) = outs else {
+                        return Err(self.error(ip, "`Ret` outside of a fn".into()));
+                    };
+                    if depth != outs {
+                        return Err(
+                            self.error(ip, format!("Returns {} values, expected {}", depth, outs))
+                        );
+                    }
+                    vec![]
+                }
+                Instr::Jmp(rel) => vec![self.target(ip, rel)?],
+                Instr::JmpIf(rel) => vec![next, self.target(ip, rel)?],
+                Instr::AJmp(addr) => vec![addr],
+                Instr::AJmpIf(addr) => vec![next, addr],
+                _ => vec![next],
+            };
+            for target in targets {
+                if target > len {
+                    return Err(
+                        self.error(ip, format!("Jump to {}, outside of the program", target))
+                    );
+                }
+                work.push((target, depth, binds));
+            }
+        }
+        Ok(())
+    }
+
+    fn target(&self, ip: usize, rel: isize) -> Result<usize, VerifyError> {
+        if ip as isize + rel < 0 {
+            return Err(self.error(ip, format!("Jump to {}, outside of the program", rel)));
+        }
+        Ok(jump(ip, rel))
+    }
+
+    /// How many values `instr` takes from the data stack and how many it
+    /// leaves there.
+    fn effect(&self, ip: usize, instr: &Instr) -> Result<(usize, usize), VerifyError> {
+        let effect = match instr {
+            Instr::Halt | Instr::Debug | Instr::Ret | Instr::Jmp(_) | Instr::AJmp(_) => (0, 0),
+            Instr::UnBind(_) => (0, 0),
+            Instr::Drop | Instr::JmpIf(_) | Instr::AJmpIf(_) => (1, 0),
+            Instr::Dup => (1, 2),
+            Instr::Over => (2, 3),
+            Instr::Swap => (2, 2),
+            Instr::Rot => (3, 3),
+            Instr::PlusI
+            | Instr::MinusI
+            | Instr::MultI
+            | Instr::DivI
+            | Instr::DivS
+            | Instr::Mod
+            | Instr::ModS
+            | Instr::Offset
+            | Instr::Lt
+            | Instr::Gt
+            | Instr::Le
+            | Instr::Ge
+            | Instr::LtS
+            | Instr::GtS
+            | Instr::LeS
+            | Instr::GeS
+            | Instr::EqI
+            | Instr::NEqI
+            | Instr::BitAnd
+            | Instr::BitOr
+            | Instr::BitXor
+            | Instr::Shl
+            | Instr::Shr
+            | Instr::Sar => (2, 1),
+            Instr::BitNot | Instr::Not | Instr::Trunc(_) | Instr::SignExt(_) => (1, 1),
+            Instr::Read(_) => (1, 1),
+            Instr::Write(_) => (2, 0),
+            Instr::LetBind(n) => (*n, 0),
+            Instr::PushBind(_) | Instr::Bind(_) | Instr::PushI32(_) | Instr::PushPtr(_) => (0, 1),
+            Instr::Sys(call) => arity(&call.signature()),
+            Instr::Call(addr) => match self.fns.get(addr) {
+                Some(signature) => arity(signature),
+                None => {
+                    return Err(self.error(
+                        ip,
+                        format!("Call to {}, which is not the start of a fn", addr),
+                    ))
+                }
+            },
+            Instr::Native(index) => {
+                let signature = self
+                    .program
+                    .natives
+                    .get(*index)
+                    .and_then(|name| self.natives.get(name));
+                match signature {
+                    Some(signature) => arity(signature),
+                    None => {
+                        return Err(self.error(ip, format!("Call to unknown native fn {}", index)))
+                    }
+                }
+            }
+        };
+        Ok(effect)
+    }
+}
+
+fn arity(signature: &Signature) -> (usize, usize) {
+    (signature.ins.len(), signature.outs.len())
+}
+
+/// Name of the instruction, without its operand.
+pub(crate) fn name(instr: &Instr) -> String {
+    let debug = format!("{:?}", instr);
+    match debug.split_once('(') {
+        Some((name, _)) => name.to_string(),
+        None => debug,
+    }
+}
@@ -0,0 +1,247 @@
+//! `chsi debug FILE [ARGS...]`: run a program one step at a time, with
+//! commands read from stdin. `help` lists them.
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, IsTerminal, Write},
+    process::exit,
+};
+
+use chs_lexer::{decode_source, DEFAULT_TAB_WIDTH};
+use chs_parser::Loc;
+use chs_vm_v2::{
+    disasm::{fn_at, instruction},
+    instructions::Bytecode,
+    Vm,
+};
+
+use crate::{load, read_file, report_trap, EXIT_TRAP, EXIT_USAGE, USAGE};
+
+const HELP: &str = "Commands:
+  break LINE | break FN   stop before line LINE or at the start of fn FN (b)
+  delete                  remove all breakpoints
+  continue                run until a breakpoint or the end (c)
+  step                    run to the next source operation (s)
+  stepi                   run one instruction (si)
+  where                   show the next instruction and its source (w)
+  stack                   print the data stack (p)
+  rstack                  print the return stack: return addresses and frames (r)
+  locals                  print the let binds of all calls (l)
+  mem ADDR [LEN]          print LEN bytes of memory at ADDR, 64 by default (x)
+  quit                    stop debugging (q)";
+
+/// Whether the program can still run, and how it ended otherwise.
+enum State {
+    Running,
+    Exited(i32),
+    Trapped,
+}
+
+struct Debugger {
+    vm: Vm,
+    filepath: String,
+    /// Lines of the source, when the file is not bytecode.
+    source: Vec<String>,
+    breakpoints: BTreeSet<usize>,
+    state: State,
+}
+
+pub fn debug(mut args: impl Iterator<Item = String>) {
+    let Some(filepath) = args.next() else {
+        eprintln!("{}", USAGE);
+        exit(EXIT_USAGE);
+    };
+    let buf = read_file(&filepath);
+    let source = match decode_source(&buf, DEFAULT_TAB_WIDTH) {
+        Ok(source) if !Bytecode::is_bytecode(&buf) => source.lines().map(String::from).collect(),
+        _ => vec![],
+    };
+    let mut vm = Vm::new(load(&filepath, 0));
+    vm.set_args(std::iter::once(filepath.clone()).chain(args).collect());
+    let mut debugger = Debugger {
+        vm,
+        filepath,
+        source,
+        breakpoints: BTreeSet::new(),
+        state: State::Running,
+    };
+    debugger.where_();
+    let stdin = io::stdin();
+    loop {
+        print!("(chs) ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        // Show the commands of a script among their output.
+        if !stdin.is_terminal() {
+            println!("{}", line.trim_end());
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["break" | "b", at] => debugger.add_breakpoint(at),
+            ["delete"] => debugger.breakpoints.clear(),
+            ["continue" | "c"] => debugger.continue_(),
+            ["step" | "s"] => debugger.step(),
+            ["stepi" | "si"] => {
+                debugger.stepi();
+                debugger.where_();
+            }
+            ["where" | "w"] => debugger.where_(),
+            ["stack" | "p"] => println!("{}", values(&debugger.vm.stack())),
+            ["rstack" | "r"] => println!("{}", values(&debugger.vm.return_stack())),
+            ["locals" | "l"] => println!("{}", values(&debugger.vm.locals())),
+            ["mem" | "x", addr] => debugger.mem(addr, "64"),
+            ["mem" | "x", addr, len] => debugger.mem(addr, len),
+            ["quit" | "q"] => break,
+            ["help" | "h"] => println!("{}", HELP),
+            _ => println!("Unknown command `{}`, try `help`", line.trim()),
+        }
+    }
+    // The exit code is the one of the program when it ended.
+    match debugger.state {
+        State::Running => {}
+        State::Exited(code) => exit(code),
+        State::Trapped => exit(EXIT_TRAP),
+    }
+}
+
+impl Debugger {
+    fn program(&self) -> &Bytecode {
+        self.vm.program()
+    }
+
+    /// Stop at the first instruction of each run of instructions of line
+    /// `at`, or at the start of fn `at`.
+    fn add_breakpoint(&mut self, at: &str) {
+        let addrs: Vec<usize> = match at.parse::<usize>() {
+            Ok(line) => {
+                let on_line =
+                    |ip: usize| self.program().loc(ip).map(|loc| loc.line()) == Some(line);
+                (0..self.program().len())
+                    .filter(|&ip| on_line(ip) && (ip == 0 || !on_line(ip - 1)))
+                    .collect()
+            }
+            Err(_) => self
+                .program()
+                .exports
+                .get(at)
+                .map(|export| export.addr)
+                .into_iter()
+                .collect(),
+        };
+        if addrs.is_empty() {
+            println!("No code for `{}`", at);
+            return;
+        }
+        println!("Breakpoint at {}", at);
+        self.breakpoints.extend(addrs);
+    }
+
+    /// Run one instruction. Returns whether the program can go on.
+    fn stepi(&mut self) -> bool {
+        if !matches!(self.state, State::Running) {
+            println!("The program is not running");
+            return false;
+        }
+        match self.vm.step() {
+            Ok(None) => true,
+            Ok(Some(status)) => {
+                println!("Program exited with code {}", status.code);
+                self.state = State::Exited(status.code);
+                false
+            }
+            Err(trap) => {
+                report_trap(&trap, &self.filepath);
+                self.state = State::Trapped;
+                false
+            }
+        }
+    }
+
+    /// Run the instructions of the current source operation, up to the first
+    /// one of another operation.
+    fn step(&mut self) {
+        let loc = self.loc();
+        while self.stepi() {
+            if self.loc() != loc || self.vm.ip() >= self.program().len() {
+                break;
+            }
+        }
+        self.where_();
+    }
+
+    fn continue_(&mut self) {
+        while self.stepi() {
+            if self.breakpoints.contains(&self.vm.ip()) {
+                println!("Breakpoint");
+                break;
+            }
+        }
+        self.where_();
+    }
+
+    fn loc(&self) -> Option<Loc> {
+        self.program().loc(self.vm.ip())
+    }
+
+    /// Print the next instruction, the fn it starts and its source line.
+    fn where_(&self) {
+        if !matches!(self.state, State::Running) {
+            return;
+        }
+        let ip = self.vm.ip();
+        if ip >= self.program().len() {
+            println!("  {:>4}  <end>", ip);
+            return;
+        }
+        if let Some(name) = fn_at(self.program(), ip) {
+            println!("{}:", name);
+        }
+        println!("  {:>4}  {}", ip, instruction(self.program(), ip));
+        if let Some(loc) = self.loc() {
+            let line = self.source.get(loc.line().wrapping_sub(1));
+            println!(
+                "{}{}: {}",
+                self.filepath,
+                loc,
+                line.map_or("", |l| l.trim())
+            );
+        }
+    }
+
+    fn mem(&self, addr: &str, len: &str) {
+        let (Ok(addr), Ok(len)) = (addr.parse::<usize>(), len.parse::<usize>()) else {
+            println!("Expected `mem ADDR [LEN]` with numbers");
+            return;
+        };
+        let mem = self.vm.memory();
+        let end = addr.saturating_add(len).min(mem.len());
+        if addr >= end {
+            println!("Memory is {} bytes", mem.len());
+            return;
+        }
+        for (i, row) in mem[addr..end].chunks(16).enumerate() {
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = row
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            println!("  {:>6}  {:<47}  {}", addr + i * 16, hex.join(" "), text);
+        }
+    }
+}
+
+/// Values of a stack, bottom first, shown signed like `debug` does.
+fn values(stack: &[u64]) -> String {
+    let values: String = stack.iter().map(|v| format!(" {} ", *v as i64)).collect();
+    format!("[{}]", values)
+}
@@ -1,22 +1,376 @@
-use std::{env, fs::File, io::Read, process::exit};
+mod debug;
 
-use chs_parser::parse_file;
-use chs_vm_v2::{compiler::compile, vm_run};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env, fs,
+    io::{self, Read, Write},
+    path::Path,
+    process::exit,
+    rc::Rc,
+};
+
+use chs_lexer::{decode_source, DEFAULT_TAB_WIDTH};
+use chs_parser::{parse_program, to_sexp, to_source, Operation};
+use chs_vm_v2::{
+    compiler::{compile_with_options, Options},
+    disasm::disassemble,
+    instructions::Bytecode,
+    natives::Natives,
+    optimize::{optimize, MAX_LEVEL},
+    trace::{Profiler, Trace},
+    verify::verify,
+    Limits, Trap, Vm,
+};
+
+/// Exit codes of `chsi` itself. A program that runs to its end exits with
+/// its own code instead.
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+const EXIT_PARSE: i32 = 4;
+const EXIT_TYPE: i32 = 5;
+const EXIT_TRAP: i32 = 6;
+
+const USAGE: &str = "Usage:
+  chsi [run] FILE [ARGS...]
+  chsi run [-O0|-O1|-O2] [--trace] [--profile] [--folded OUT] [--fuel N]
+           [--max-stack N] [--max-memory N] FILE [ARGS...]
+  chsi build [-O0|-O1|-O2] [--strip] FILE [-o OUT]
+  chsi disasm [-O0|-O1|-O2] FILE
+  chsi debug FILE [ARGS...]
+  chsi fmt [--check] [FILE...]
+  chsi dump [--source] FILE";
 
 fn main() {
     let mut args = env::args();
     let _program = args.next().expect("Program always provided.");
-    if let Some(filepath) = args.next() {
-        if let Ok(mut file) = File::open(filepath.clone()) {
-            let mut buf = Vec::new();
-            let _ = file.read_to_end(&mut buf);
-            let program = parse_file(buf, filepath);
-            // dbg!(program);
-            type_check::check_program(&program);
-            let b = compile(program);
-            vm_run(b);
+    let Some(filepath) = args.next() else {
+        eprintln!("{}", USAGE);
+        exit(EXIT_USAGE);
+    };
+    match filepath.as_str() {
+        "fmt" => fmt(args),
+        "dump" => dump(args),
+        "build" => build(args),
+        "disasm" => disasm(args),
+        "debug" => debug::debug(args),
+        "run" => run_with_options(args),
+        _ => run(&filepath, args, RunOptions::default()),
+    }
+}
+
+/// How `chsi run` runs a program and what it reports about the run.
+#[derive(Default)]
+struct RunOptions {
+    limits: Limits,
+    /// Level to optimize the program by before it runs.
+    opt: u8,
+    /// Write each instruction to stderr.
+    trace: bool,
+    /// Write the profile to stderr at the end.
+    profile: bool,
+    /// Write the call stacks in folded format to this file at the end.
+    folded: Option<String>,
+}
+
+/// `chsi run [OPTIONS] FILE [ARGS...]`. The limits of `--fuel`,
+/// `--max-stack` and `--max-memory` are in instructions, values on each
+/// stack and bytes.
+fn run_with_options(mut args: impl Iterator<Item = String>) {
+    let mut options = RunOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => options.trace = true,
+            "--profile" => options.profile = true,
+            "--folded" => options.folded = args.next(),
+            "--fuel" => options.limits.fuel = Some(number(args.next())),
+            "--max-stack" => {
+                let values = number(args.next()) as usize;
+                options.limits.stack = values;
+                options.limits.return_stack = values;
+            }
+            "--max-memory" => options.limits.memory = Some(number(args.next()) as usize),
+            _ if arg.starts_with("-O") => options.opt = opt_level(&arg),
+            _ => return run(&arg, args, options),
+        }
+    }
+    eprintln!("{}", USAGE);
+    exit(EXIT_USAGE);
+}
+
+/// `chsi [run] FILE [ARGS...]`: run a source or bytecode file with the
+/// arguments after it, and exit with its exit code.
+fn run(filepath: &str, args: impl Iterator<Item = String>, options: RunOptions) {
+    let bytecode = load(filepath, options.opt);
+    let mut vm = match Vm::with_limits(bytecode, Natives::default(), options.limits) {
+        Ok(vm) => vm,
+        Err(trap) => {
+            report_trap(&trap, filepath);
+            exit(EXIT_TRAP);
+        }
+    };
+    // The program gets its own path and the arguments after it.
+    vm.set_args(std::iter::once(filepath.to_string()).chain(args).collect());
+    let profiler = Rc::new(RefCell::new(Profiler::default()));
+    if options.trace {
+        vm.set_tracer(Trace::new(io::stderr()));
+    } else if options.profile || options.folded.is_some() {
+        vm.set_tracer(profiler.clone());
+    }
+    let result = vm.run();
+    if options.profile {
+        eprint!("{}", profiler.borrow().report());
+    }
+    if let Some(out) = &options.folded {
+        if let Err(e) = fs::write(out, profiler.borrow().folded()) {
+            eprintln!("Error:\n  {} in {}", e, out);
+            exit(EXIT_IO);
+        }
+    }
+    match result {
+        Ok(status) => exit(status.code),
+        Err(trap) => {
+            report_trap(&trap, filepath);
+            exit(EXIT_TRAP);
+        }
+    }
+}
+
+/// `chsi build [-O0|-O1|-O2] [--strip] FILE [-o OUT]`: compile a source file
+/// to bytecode, written to OUT or to FILE with the extension `.chsb`.
+/// `--strip` leaves out the source locations used to report errors.
+fn build(mut args: impl Iterator<Item = String>) {
+    let mut opt = 0;
+    let mut strip = false;
+    let mut filepath = None;
+    let mut out = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--strip" => strip = true,
+            "-o" => out = args.next(),
+            _ if arg.starts_with("-O") => opt = opt_level(&arg),
+            _ => filepath = Some(arg),
+        }
+    }
+    let Some(filepath) = filepath else {
+        eprintln!("{}", USAGE);
+        exit(EXIT_USAGE);
+    };
+    let out = out.unwrap_or_else(|| {
+        Path::new(&filepath)
+            .with_extension("chsb")
+            .to_string_lossy()
+            .into_owned()
+    });
+    let mut bytecode = compile_source(&read_file(&filepath), &filepath, opt);
+    if strip {
+        bytecode.locs.clear();
+    }
+    if let Err(e) = fs::write(&out, bytecode.to_bytes()) {
+        eprintln!("Error:\n  {} in {}", e, out);
+        exit(EXIT_IO);
+    }
+}
+
+/// `chsi disasm [-O0|-O1|-O2] FILE`: print the compiled instructions of a
+/// source or bytecode file, optimized by the level given.
+fn disasm(args: impl Iterator<Item = String>) {
+    let mut opt = 0;
+    let mut filepath = None;
+    for arg in args {
+        match arg.as_str() {
+            _ if arg.starts_with("-O") => opt = opt_level(&arg),
+            _ => filepath = Some(arg),
+        }
+    }
+    let Some(filepath) = filepath else {
+        eprintln!("{}", USAGE);
+        exit(EXIT_USAGE);
+    };
+    print!("{}", disassemble(&load(&filepath, opt)));
+}
+
+/// Compile a source file, or load and verify a bytecode file, and optimize
+/// it by `opt`.
+fn load(filepath: &str, opt: u8) -> Bytecode {
+    let buf = read_file(filepath);
+    if !Bytecode::is_bytecode(&buf) {
+        return compile_source(&buf, filepath, opt);
+    }
+    let bytecode = match Bytecode::from_bytes(&buf) {
+        Ok(bytecode) => bytecode,
+        Err(e) => {
+            eprintln!("Error:\n  {} in {}", e, filepath);
+            exit(EXIT_PARSE);
+        }
+    };
+    if let Err(e) = verify(&bytecode) {
+        let loc = e.loc.map(|loc| loc.to_string()).unwrap_or_default();
+        eprintln!("Error:\n  {} in {}{}", e, filepath, loc);
+        exit(EXIT_PARSE);
+    }
+    optimize(&bytecode, opt)
+}
+
+/// The number after an option, or exit when there is none.
+fn number(arg: Option<String>) -> u64 {
+    match arg.map(|arg| arg.parse()) {
+        Some(Ok(n)) => n,
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+/// The level of an option like `-O2`, or exit when it is not one.
+fn opt_level(arg: &str) -> u8 {
+    match arg["-O".len()..].parse() {
+        Ok(level) if level <= MAX_LEVEL => level,
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+fn read_file(filepath: &str) -> Vec<u8> {
+    match fs::read(filepath) {
+        Ok(buf) => buf,
+        Err(e) => {
+            eprintln!("Error:\n  {} in {}", e, filepath);
+            exit(EXIT_IO);
+        }
+    }
+}
+
+/// Parse `buf`, or report why it cannot be and exit.
+fn parse_source(buf: &[u8], filepath: &str) -> Vec<Operation> {
+    let input = match decode_source(buf, DEFAULT_TAB_WIDTH) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Error:\n  {} in {}{}", e, filepath, e.loc);
+            exit(EXIT_PARSE);
+        }
+    };
+    match parse_program(input, filepath) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(EXIT_PARSE);
+        }
+    }
+}
+
+/// Parse, type check and compile `buf` optimized by `opt`, or report the
+/// first error and exit.
+fn compile_source(buf: &[u8], filepath: &str, opt: u8) -> Bytecode {
+    let mut program = parse_source(buf, filepath);
+    if let Err(e) = type_check::check_program(&mut program) {
+        eprintln!("Error:\n  {} in {}{}", e, filepath, e.loc);
+        exit(EXIT_TYPE);
+    }
+    let bytecode = compile_with_options(&program, &HashMap::default(), Options::level(opt));
+    optimize(&bytecode, opt)
+}
+
+fn report_trap(trap: &Trap, filepath: &str) {
+    let loc = trap.loc.map(|loc| loc.to_string()).unwrap_or_default();
+    eprintln!("Error:\n  {} in {}{}", trap.kind, filepath, loc);
+    let stack: String = trap
+        .stack
+        .iter()
+        .map(|v| format!(" {} ", *v as i64))
+        .collect();
+    eprintln!("  Data Stack: [{}]", stack);
+}
+
+/// `chsi dump [--source] FILE`: print the parsed program as S-expressions,
+/// or as canonical source with `--source`.
+fn dump(args: impl Iterator<Item = String>) {
+    let mut source = false;
+    let mut filepath = None;
+    for arg in args {
+        match arg.as_str() {
+            "--source" => source = true,
+            _ => filepath = Some(arg),
+        }
+    }
+    let Some(filepath) = filepath else {
+        eprintln!("Usage: chsi dump [--source] FILE");
+        exit(EXIT_USAGE);
+    };
+    let program = parse_source(&read_file(&filepath), &filepath);
+    if source {
+        print!("{}", to_source(&program));
+    } else {
+        print!("{}", to_sexp(&program));
+    }
+}
+
+/// `chsi fmt [--check] [FILE...]`: format the files in place, or stdin to
+/// stdout when no file is given. With `--check` nothing is written and the
+/// exit code is 1 when some file is not formatted.
+fn fmt(args: impl Iterator<Item = String>) {
+    let mut check = false;
+    let mut files: Vec<String> = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            _ => files.push(arg),
+        }
+    }
+
+    if files.is_empty() {
+        let mut buf = Vec::new();
+        if let Err(e) = io::stdin().read_to_end(&mut buf) {
+            eprintln!("Error:\n  {} in <stdin>", e);
+            exit(EXIT_IO);
+        }
+        let formatted = format_or_exit(&buf, "<stdin>");
+        if check {
+            if formatted.as_bytes() != buf {
+                eprintln!("<stdin> is not formatted");
+                exit(1);
+            }
         } else {
-            exit(-1)
+            let _ = io::stdout().write_all(formatted.as_bytes());
+        }
+        return;
+    }
+
+    let mut unformatted = false;
+    for filepath in files {
+        let buf = match fs::read(&filepath) {
+            Ok(buf) => buf,
+            Err(e) => {
+                eprintln!("Error:\n  {} in {}", e, filepath);
+                exit(EXIT_IO);
+            }
+        };
+        let formatted = format_or_exit(&buf, &filepath);
+        if formatted.as_bytes() == buf {
+            continue;
+        }
+        if check {
+            eprintln!("{} is not formatted", filepath);
+            unformatted = true;
+        } else if let Err(e) = fs::write(&filepath, formatted) {
+            eprintln!("Error:\n  {} in {}", e, filepath);
+            exit(EXIT_IO);
+        }
+    }
+    if unformatted {
+        exit(1);
+    }
+}
+
+fn format_or_exit(buf: &[u8], filepath: &str) -> String {
+    match chs_fmt::format_file(buf, filepath) {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(EXIT_PARSE);
         }
     }
 }
@@ -1,5 +1,5 @@
 use std::{
-    alloc::{alloc, alloc_zeroed, dealloc, Layout},
+    alloc::{alloc, alloc_zeroed, dealloc, handle_alloc_error, Layout},
     io::Write,
 };
 
@@ -19,8 +19,10 @@ pub struct Memory {
 /// can cause the program to crash, because of malformation of the type.
 ///
 /// In implemetation below the program will crash because of the is no way tho construct `Foo` from `1u8`.
-/// ```
+/// ```no_run
+/// # use memory::{Memory, MemoryAllowed};
 /// #[repr(u8)]
+/// #[derive(Clone, Copy)]
 /// enum Foo {
 ///     Bar = 0u8,
 /// }
@@ -30,6 +32,9 @@ pub struct Memory {
 /// mem.read::<Foo>(0);
 /// ```
 ///
+/// # Safety
+///
+/// Every bit pattern of `size_of::<T>()` bytes must be a valid `T`.
 pub unsafe trait MemoryAllowed {}
 unsafe impl MemoryAllowed for u8 {}
 unsafe impl MemoryAllowed for u16 {}
@@ -58,12 +63,28 @@ impl Memory {
     pub fn new(size: usize) -> Self {
         let size = if size > MEM_MIN { size } else { MEM_MIN };
         let layout = Layout::from_size_align(size, size_of::<u8>()).unwrap();
-        Self {
-            inner: unsafe { alloc_zeroed(layout) }, //vec![0; size],
+        Self::try_new(size).unwrap_or_else(|| handle_alloc_error(layout))
+    }
+
+    /// Like `new`, or `None` when the system does not have `size` bytes.
+    /// ```
+    /// # use memory::Memory;
+    /// assert!(Memory::try_new(16).is_some());
+    /// assert!(Memory::try_new(usize::MAX).is_none());
+    /// ```
+    pub fn try_new(size: usize) -> Option<Self> {
+        let size = if size > MEM_MIN { size } else { MEM_MIN };
+        let layout = Layout::from_size_align(size, size_of::<u8>()).ok()?;
+        let inner = unsafe { alloc_zeroed(layout) };
+        if inner.is_null() {
+            return None;
+        }
+        Some(Self {
+            inner,
             layout,
             write_pos: 0,
             size,
-        }
+        })
     }
 
     pub fn realloc(&mut self, new_size: usize) {
@@ -124,9 +145,7 @@ impl Memory {
     }
 
     pub fn to_ptr<T: Copy + MemoryAllowed>(&self) -> *mut T {
-        unsafe {
-            return self.inner.add(self.write_pos) as *mut T;
-        }
+        unsafe { self.inner.add(self.write_pos) as *mut T }
     }
 
     pub fn set_write_pos(&mut self, pos: usize) {
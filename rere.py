@@ -99,8 +99,38 @@ def update_test_list(test_list_path):
     if not os.path.isfile(test_list_path):
         print(f"INFO: {test_list_path} not exist. Creating one.")
     with open(test_list_path, "w") as f:
-        for name in os.listdir("tests"):
-            f.write(f"./target/debug/chsi tests/{name}\n")
+        for name in sorted(os.listdir("tests")):
+            if os.path.isfile(f"tests/{name}"):
+                f.write(f"./target/debug/chsi tests/{name}\n")
+        # Optimized programs must behave like the ones above.
+        for name in sorted(os.listdir("tests")):
+            if os.path.isfile(f"tests/{name}"):
+                f.write(f"./target/debug/chsi run -O2 tests/{name}\n")
+        for name in sorted(os.listdir("tests/fmt")):
+            f.write(f"./target/debug/chsi fmt < tests/fmt/{name}\n")
+        for name in sorted(os.listdir("tests/dump")):
+            f.write(f"./target/debug/chsi dump tests/dump/{name}\n")
+            f.write(f"./target/debug/chsi dump --source tests/dump/{name}\n")
+        for name in sorted(os.listdir("tests/disasm")):
+            f.write(f"./target/debug/chsi disasm tests/disasm/{name}\n")
+        for name in sorted(os.listdir("tests/debug")):
+            stem, ext = os.path.splitext(name)
+            if ext == ".chs":
+                f.write(f"./target/debug/chsi debug tests/debug/{name} < tests/debug/{stem}.in\n")
+        for name in sorted(os.listdir("tests/trace")):
+            f.write(f"./target/debug/chsi run --trace tests/trace/{name}\n")
+            f.write(f"./target/debug/chsi run --profile --folded /dev/stdout tests/trace/{name}\n")
+        for name in sorted(os.listdir("tests/limits")):
+            f.write(f"./target/debug/chsi run --fuel 1000 --max-stack 16 --max-memory 64 tests/limits/{name}\n")
+        # And under the default limits.
+        for name in sorted(os.listdir("tests/limits")):
+            f.write(f"./target/debug/chsi run --fuel 1000 tests/limits/{name}\n")
+        for name in sorted(os.listdir("tests/opt")):
+            f.write(f"./target/debug/chsi disasm -O2 tests/opt/{name}\n")
+            f.write(f"./target/debug/chsi run -O2 tests/opt/{name}\n")
+        for name in sorted(os.listdir("tests/build")):
+            out = f"target/{os.path.splitext(name)[0]}.chsb"
+            f.write(f"./target/debug/chsi build tests/build/{name} -o {out} && ./target/debug/chsi run {out} arg\n")
 
 if __name__ == "__main__":
     program_name, *argv = sys.argv
@@ -1,328 +1,361 @@
-use std::{collections::HashMap, process::exit, rc::Rc};
+use std::{collections::HashMap, fmt, rc::Rc};
 
-use chs_parser::{DataType, Operation};
+use chs_parser::{
+    visit::make_mut, DataType, Loc, MutVisitor, Operation, OperationKind, Signature, Syscall,
+};
 
 type TypeStack = Vec<DataType>;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeError {
+    /// Location of the operation with the wrong types.
+    pub loc: Loc,
+    pub message: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+type CheckResult<T> = Result<T, String>;
+
 #[derive(Debug, Default)]
 struct TypeContext {
     stack: TypeStack,
-    ip: usize,
-    fndefs: HashMap<String, (Rc<[DataType]>, Rc<[DataType]>)>,
+    fndefs: HashMap<String, Signature>,
     memdefs: HashMap<String, usize>,
     binds: HashMap<String, DataType>,
+    /// The first error found, after which nothing else is checked.
+    error: Option<TypeError>,
 }
 
-pub fn check_program(program: &Vec<Operation>) {
-    let mut ctx = TypeContext::default(); // Inicializar o contexto
-    check_program_ops(&mut ctx, program); // Analize de operações
-    if ctx.stack.len() != 0 {
-        eprintln!("Unhandled data on stack at the end of program");
-        dbg!(ctx.stack);
-        exit(-1);
+impl MutVisitor for TypeContext {
+    fn visit_op_mut(&mut self, op: &mut Operation) {
+        if self.error.is_none() {
+            if let Err(message) = check_op(self, op) {
+                self.error.get_or_insert(TypeError {
+                    loc: op.loc,
+                    message,
+                });
+            }
+        }
     }
 }
 
-fn check_program_ops(ctx: &mut TypeContext, program: &Vec<Operation>) {
-    while ctx.ip < program.len() {
-        match &program[ctx.ip] {
-            Operation::Debug => {
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::Str(_) => {
-                ctx.stack.push(DataType::Int);
-                ctx.stack.push(DataType::Ptr);
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::Alloc(name, _) => {
-                ctx.memdefs.insert(name.clone(), 0);
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::Word(name) => {
-                if let Some((ins, outs)) = ctx.fndefs.get(name) {
-                    if ins.len() > ctx.stack.len() {
-                        eprintln!("Unsifsient data on stack for fn {}", name);
-                        exit(-1);
-                    }
-                    for (expect, actual) in ins.iter().rev().zip(ctx.stack.iter().rev()) {
-                        if actual != expect {
-                            eprintln!("Expected Type {:?} got {:?} in {}", expect, actual, name);
-                            exit(-1);
-                        }
-                    }
-                    ctx.stack
-                        .truncate(ctx.stack.len().saturating_sub(ins.len()));
-                    ctx.stack.extend(outs.iter());
-                } else if ctx.memdefs.contains_key(name) {
-                    ctx.stack.push(DataType::Ptr);
-                } else if ctx.binds.contains_key(name) {
-                    ctx.stack.push(ctx.binds.get(name).unwrap().clone());
-                } else {
-                    eprintln!("Unkwon word {}", name);
-                    exit(-1);
-                }
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::Intrinsic(s) => {
-                check_intrinsic(s, ctx);
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::Sys(s) => {
-                check_sys_fn(s, ctx);
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::PushI(_) => {
-                ctx.stack.push(DataType::Int);
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::Write(_) => {
-                // (ptr int -> )
-                if ctx.stack.len() < 2 {
-                    eprintln!("Not enough arguments for `!`.");
-                    exit(-1);
-                }
-                if let Some(frame) = ctx.stack.last() {
-                    // b
-                    if *frame != DataType::Ptr {
-                        eprintln!("Expected Type `ptr` for `!`, found {:?}", frame);
-                        eprintln!("Type Stack: ");
-                        eprintln!("\tActual: {:?}", ctx.stack);
-                        eprintln!("\tExpected: {:?}", [DataType::Int, DataType::Ptr]);
-                        exit(-1);
-                    }
-                    ctx.stack.pop();
-                }
-                if let Some(frame) = ctx.stack.pop() {
-                    // a
-                    if frame != DataType::Int {
-                        eprintln!("Expected Type `int` for `!`, found {:?}", frame);
-                        eprintln!("Type Stack: {:?}", ctx.stack);
-                        exit(-1);
-                    }
-                }
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::Read(_) => {
-                // (ptr -> int)
-                if ctx.stack.len() < 1 {
-                    eprintln!("Not enough arguments for `@` TODO");
-                    exit(-1);
-                }
-                if let Some(frame) = ctx.stack.pop() {
-                    // a
-                    if frame != DataType::Ptr {
-                        eprintln!("Typeof b `@` Actual: {:?} TODO", frame);
-                        exit(-1);
-                    }
-                }
-                ctx.stack.push(DataType::Int);
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::If(then) => {
-                // (bool ->)
-                if let Some(frame) = ctx.stack.last() {
-                    if *frame != DataType::Bool {
-                        eprintln!(
-                            "Expected type on `if` must be Bool. Actual: {:?} TODO",
-                            frame
-                        );
-                        exit(-1);
-                    }
-                } else {
-                    eprintln!("Empyt stack on `if`",);
-                    exit(-1);
-                }
-                let _ = ctx.stack.pop();
-                let next_ip = ctx.ip + 1;
-                ctx.ip = 0;
-                check_program_ops(ctx, &then.to_vec());
-                ctx.ip = next_ip;
-                continue;
-            }
-            Operation::IfElse(then, else_) => {
-                // (bool ->)
-                if let Some(frame) = ctx.stack.last() {
-                    if *frame != DataType::Bool {
-                        eprintln!(
-                            "Expected type on `if` must be Bool. Actual: {:?} TODO",
-                            frame
-                        );
-                        exit(-1);
-                    }
-                } else {
-                    eprintln!("Empyt stack on `if`",);
-                    exit(-1);
-                }
-                let _ = ctx.stack.pop();
-                let next_ip = ctx.ip + 1;
-                ctx.ip = 0;
-                check_program_ops(ctx, &then.to_vec());
-                ctx.ip = 0;
-                check_program_ops(ctx, &else_.to_vec());
-                ctx.ip = next_ip;
-                continue;
-            }
-            Operation::While(cond, body) => {
-                let next_ip = ctx.ip + 1;
-                let tmp = ctx.stack.clone();
-                ctx.ip = 0;
-                check_program_ops(ctx, &cond.to_vec());
-                if let Some(frame) = ctx.stack.pop() {
-                    if frame != DataType::Bool {
-                        eprintln!("While TODO");
-                        exit(-1);
-                    }
-                } else {
-                    eprintln!("While TODO");
-                    exit(-1);
-                }
-                ctx.ip = 0;
-                check_program_ops(ctx, &body.to_vec());
-                if ctx.stack.len() != tmp.len() {
-                    eprintln!("Unhandled data on stack after `while`");
-                    eprintln!("Type Stack: ");
-                    eprintln!("\tActual: {:?}", ctx.stack);
-                    eprintln!("\tExpected: {:?}", tmp);
-                    exit(-1);
+/// Check the operations of a block, stopping at the first error.
+fn check_block(ctx: &mut TypeContext, ops: &mut Rc<[Operation]>) -> CheckResult<()> {
+    ctx.visit_ops_mut(make_mut(ops));
+    match ctx.error {
+        // Already recorded; the operation around the block gets no error.
+        Some(_) => Err(String::new()),
+        None => Ok(()),
+    }
+}
+
+/// Check the types of `program` and record on each intrinsic the type of its
+/// operands, for the compiler to choose signed or unsigned instructions.
+pub fn check_program(program: &mut [Operation]) -> Result<(), TypeError> {
+    check_program_with(program, &HashMap::default())
+}
+
+/// Like `check_program`, for a program that can also call the host functions
+/// in `natives` as words.
+pub fn check_program_with(
+    program: &mut [Operation],
+    natives: &HashMap<String, Signature>,
+) -> Result<(), TypeError> {
+    let mut ctx = TypeContext {
+        fndefs: natives.clone(),
+        ..Default::default()
+    }; // Inicializar o contexto
+    ctx.visit_ops_mut(program); // Analize de operações
+    if let Some(e) = ctx.error {
+        return Err(e);
+    }
+    if !ctx.stack.is_empty() {
+        return Err(TypeError {
+            loc: program.last().map_or_else(Loc::default, |op| op.loc),
+            message: format!(
+                "Unhandled data on stack at the end of program: {:?}",
+                ctx.stack
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn check_op(ctx: &mut TypeContext, op: &mut Operation) -> CheckResult<()> {
+    match &mut op.kind {
+        OperationKind::Debug => {}
+        OperationKind::Str(_) => {
+            ctx.stack.push(DataType::Int);
+            ctx.stack.push(DataType::Ptr);
+        }
+        OperationKind::Const(..) => {}
+        OperationKind::Alloc(name, ..) => {
+            ctx.memdefs.insert(name.clone(), 0);
+        }
+        OperationKind::Word(name) => {
+            if let Some(Signature { ins, outs }) = ctx.fndefs.get(name) {
+                if ins.len() > ctx.stack.len() {
+                    return Err(format!("Unsifsient data on stack for fn {}", name));
                 }
-                for (expect, actual) in ctx.stack.iter().rev().zip(tmp.iter().rev()) {
+                for (expect, actual) in ins.iter().rev().zip(ctx.stack.iter().rev()) {
                     if actual != expect {
-                        eprintln!("Expected Type {:?} got {:?}", expect, actual);
-                        exit(-1);
+                        return Err(format!(
+                            "Expected Type {:?} got {:?} in {}",
+                            expect, actual, name
+                        ));
                     }
                 }
-                ctx.stack = tmp;
-                ctx.ip = next_ip;
-                continue;
-            }
-            Operation::Bind(i) => {
-                // (any. . . -> any)
-                if ctx.stack.len() < (*i) as usize {
-                    eprintln!("Bind TODO");
-                    exit(-1);
-                }
-                let a = ctx.stack[ctx.stack.len().saturating_sub((*i) as usize)];
-                ctx.stack.push(a);
-                ctx.ip += 1;
-                continue;
-            }
-            Operation::Assing(_, _) => todo!(),
-            Operation::Let(names, body) => {
-                let next_ip = ctx.ip + 1;
-                if ctx.stack.len() < names.len() {
-                    eprintln!("Unsuficient data on stack for `let`");
-                    eprintln!("Type Stack: ");
-                    eprintln!("\tActual: {:?}", ctx.stack.len());
-                    eprintln!("\tExpected: {:?}", names.len());
-                    exit(-1);
-                }
-                for name in names.iter().rev() {
-                    if let Some(_) = ctx.binds.insert(name.clone(), ctx.stack.pop().unwrap()){
-                        eprintln!("Redefinition of word {}", name);
-                        exit(-1);
-                    }
-                }
-                ctx.ip = 0;
-                check_program_ops(ctx, &body.to_vec());
-                for name in names.iter().rev() {
-                    ctx.binds.remove(name);
+                ctx.stack
+                    .truncate(ctx.stack.len().saturating_sub(ins.len()));
+                ctx.stack.extend(outs.iter());
+            } else if ctx.memdefs.contains_key(name) {
+                ctx.stack.push(DataType::Ptr);
+            } else if ctx.binds.contains_key(name) {
+                ctx.stack.push(*ctx.binds.get(name).unwrap());
+            } else {
+                return Err(format!("Unkwon word {}", name));
+            }
+        }
+        OperationKind::Intrinsic(s, typ) => {
+            *typ = check_intrinsic(s, ctx)?;
+        }
+        OperationKind::Sys(s) => {
+            check_sys_fn(*s, ctx)?;
+        }
+        OperationKind::PushI(_) => {
+            ctx.stack.push(DataType::Int);
+        }
+        OperationKind::PushBool(_) => {
+            ctx.stack.push(DataType::Bool);
+        }
+        OperationKind::Write(bytes) => {
+            // (a ptr -> ) for a stored in `bytes` bits
+            let types = memory_types(*bytes)?;
+            if ctx.stack.len() < 2 {
+                return Err("Not enough arguments for `!`.".to_string());
+            }
+            if let Some(frame) = ctx.stack.last() {
+                // b
+                if *frame != DataType::Ptr {
+                    return Err(format!(
+                        "Expected Type `ptr` for `!`, found {:?}. Type Stack: {:?}",
+                        frame, ctx.stack
+                    ));
                 }
-                ctx.ip = next_ip;
-                continue;
-            }
-            Operation::Fn(name, _, ins, outs, body) => {
-                let redef = ctx.fndefs.insert(name.clone(), (ins.clone(), outs.clone()));
-                if redef.is_some() {
-                    eprintln!("Redefinition of fn {}", name);
-                    exit(-1);
+                ctx.stack.pop();
+            }
+            if let Some(frame) = ctx.stack.pop() {
+                // a
+                if !types.contains(&frame) {
+                    return Err(format!(
+                        "Expected Type `{}` for `!{}`, found {:?}. Type Stack: {:?}",
+                        types[0], bytes, frame, ctx.stack
+                    ));
                 }
-                let mut fn_ctx = TypeContext::default();
-                fn_ctx.stack.extend(&ins.to_vec());
-                check_program_ops(&mut fn_ctx, &body.to_vec());
-                if fn_ctx.stack.len() != outs.len() {
-                    eprintln!("Unhandled data on stack in fn {}", name);
-                    eprintln!("Type Stack: ");
-                    eprintln!("\tActual: {:?}", fn_ctx.stack);
-                    eprintln!("\tExpected: {:?}", ctx.stack);
-                    exit(-1);
+            }
+        }
+        OperationKind::Read(bytes) => {
+            // (ptr -> a) for a stored in `bytes` bits
+            let typ = memory_types(*bytes)?[0];
+            if ctx.stack.is_empty() {
+                return Err("Not enough arguments for `@` TODO".to_string());
+            }
+            if let Some(frame) = ctx.stack.pop() {
+                // a
+                if frame != DataType::Ptr {
+                    return Err(format!("Typeof b `@` Actual: {:?} TODO", frame));
                 }
-                for (expect, actual) in outs.iter().rev().zip(fn_ctx.stack.iter().rev()) {
-                    if actual != expect {
-                        eprintln!("Expected Type {:?} got {:?} in {}", expect, actual, name);
-                        exit(-1);
-                    }
+            }
+            ctx.stack.push(typ);
+        }
+        OperationKind::Cast(typ) => {
+            // (a -> typ) between integers and `char`
+            let Some(frame) = ctx.stack.pop() else {
+                return Err(format!("Empyt stack on cast to `{}`", typ));
+            };
+            if frame.bits().is_none() || typ.bits().is_none() {
+                return Err(format!("Cannot cast `{}` to `{}`", frame, typ));
+            }
+            ctx.stack.push(*typ);
+        }
+        OperationKind::If(then) => {
+            // (bool ->)
+            if let Some(frame) = ctx.stack.last() {
+                if *frame != DataType::Bool {
+                    return Err(format!(
+                        "Expected type on `if` must be Bool. Actual: {:?} TODO",
+                        frame
+                    ));
                 }
-                ctx.ip += 1;
-                continue;
+            } else {
+                return Err("Empyt stack on `if`".to_string());
             }
+            let _ = ctx.stack.pop();
+            check_block(ctx, then)?;
         }
-    }
-}
-
-fn check_sys_fn(s: &String, ctx: &mut TypeContext) {
-    match s.as_str() {
-        "write" => {
-            //(int ptr int -> )
-            if ctx.stack.len() < 3 {
-                eprintln!("Write TODO");
-                exit(-1);
+        OperationKind::IfElse(then, else_) => {
+            // (bool ->)
+            if let Some(frame) = ctx.stack.last() {
+                if *frame != DataType::Bool {
+                    return Err(format!(
+                        "Expected type on `if` must be Bool. Actual: {:?} TODO",
+                        frame
+                    ));
+                }
+            } else {
+                return Err("Empyt stack on `if`".to_string());
             }
+            let _ = ctx.stack.pop();
+            check_block(ctx, then)?;
+            check_block(ctx, else_)?;
+        }
+        OperationKind::While(cond, body) => {
+            let tmp = ctx.stack.clone();
+            check_block(ctx, cond)?;
             if let Some(frame) = ctx.stack.pop() {
-                // c
-                if frame != DataType::Int {
-                    eprintln!("Expected type Int `write` Actual: {:?} TODO", frame);
-                    exit(-1);
+                if frame != DataType::Bool {
+                    return Err("While TODO".to_string());
                 }
+            } else {
+                return Err("While TODO".to_string());
             }
-            if let Some(frame) = ctx.stack.pop() {
-                // b
-                if frame != DataType::Ptr {
-                    eprintln!("Expected type Ptr `write` Actual: {:?} TODO", frame);
-                    exit(-1);
+            check_block(ctx, body)?;
+            if ctx.stack.len() != tmp.len() {
+                return Err(format!(
+                    "Unhandled data on stack after `while`, expected {:?} but got {:?}",
+                    tmp, ctx.stack
+                ));
+            }
+            for (expect, actual) in ctx.stack.iter().rev().zip(tmp.iter().rev()) {
+                if actual != expect {
+                    return Err(format!("Expected Type {:?} got {:?}", expect, actual));
                 }
             }
-            if let Some(frame) = ctx.stack.pop() {
-                // a
-                if frame != DataType::Int {
-                    eprintln!("Expected type Int `write` Actual: {:?} TODO", frame);
-                    exit(-1);
+            ctx.stack = tmp;
+        }
+        OperationKind::Bind(i) => {
+            // (any. . . -> any)
+            if *i == 0 {
+                return Err("Binds start at `&1`, `&0` is not a value".to_string());
+            }
+            if ctx.stack.len() < (*i) as usize {
+                return Err(format!(
+                    "Unsuficient data on stack for `&{}`, expected {} values but got {}",
+                    i,
+                    i,
+                    ctx.stack.len()
+                ));
+            }
+            let a = ctx.stack[ctx.stack.len().saturating_sub((*i) as usize)];
+            ctx.stack.push(a);
+        }
+        OperationKind::Assing(name, _) => {
+            return Err(format!("Assignment to `{}` is not supported yet", name));
+        }
+        OperationKind::Let(names, body) => {
+            if ctx.stack.len() < names.len() {
+                return Err(format!(
+                    "Unsuficient data on stack for `let`, expected {} values but got {}",
+                    names.len(),
+                    ctx.stack.len()
+                ));
+            }
+            for name in names.iter().rev() {
+                if ctx
+                    .binds
+                    .insert(name.clone(), ctx.stack.pop().unwrap())
+                    .is_some()
+                {
+                    return Err(format!("Redefinition of word {}", name));
+                }
+            }
+            check_block(ctx, body)?;
+            for name in names.iter().rev() {
+                ctx.binds.remove(name);
+            }
+        }
+        OperationKind::Fn(name, _, ins, outs, body, ..) => {
+            let signature = Signature {
+                ins: ins.clone(),
+                outs: outs.clone(),
+            };
+            let redef = ctx.fndefs.insert(name.clone(), signature);
+            if redef.is_some() {
+                return Err(format!("Redefinition of fn {}", name));
+            }
+            // `main` gives the exit code of the program, if anything.
+            if name == "main" && !(ins.is_empty() && matches!(**outs, [] | [DataType::Int])) {
+                return Err("Expected `fn main : ->` or `fn main : -> int`".to_string());
+            }
+            // The body can use the fns, including itself, and memory defined
+            // before it, but not the binds around it.
+            let mut fn_ctx = TypeContext {
+                fndefs: ctx.fndefs.clone(),
+                memdefs: ctx.memdefs.clone(),
+                ..Default::default()
+            };
+            fn_ctx.stack.extend(ins.iter());
+            if let Err(e) = check_block(&mut fn_ctx, body) {
+                ctx.error = fn_ctx.error;
+                return Err(e);
+            }
+            if fn_ctx.stack.len() != outs.len() {
+                return Err(format!(
+                    "Unhandled data on stack in fn {}, expected {:?} but got {:?}",
+                    name, outs, fn_ctx.stack
+                ));
+            }
+            for (expect, actual) in outs.iter().rev().zip(fn_ctx.stack.iter().rev()) {
+                if actual != expect {
+                    return Err(format!(
+                        "Expected Type {:?} got {:?} in {}",
+                        expect, actual, name
+                    ));
                 }
             }
         }
-        a => {
-            eprintln!("Unkwon sys fn `{}` in type check", a);
-            exit(-1);
+    }
+    Ok(())
+}
+
+fn check_sys_fn(call: Syscall, ctx: &mut TypeContext) -> CheckResult<()> {
+    let Signature { ins, outs } = call.signature();
+    if ctx.stack.len() < ins.len() {
+        return Err(format!("Unsufficient data on stack for `${}`", call));
+    }
+    for expect in ins.iter().rev() {
+        let frame = ctx.stack.pop().expect("checked above");
+        if frame != *expect {
+            return Err(format!(
+                "Expected type {:?} `{}` Actual: {:?} TODO",
+                expect, call, frame
+            ));
         }
     }
+    ctx.stack.extend(outs.iter());
+    Ok(())
 }
 
-fn check_intrinsic(s: &String, ctx: &mut TypeContext) {
-    match s.as_str() {
+/// Returns the type of the operands of arithmetic, comparisons and bitwise
+/// operations.
+fn check_intrinsic(s: &str, ctx: &mut TypeContext) -> CheckResult<Option<DataType>> {
+    match s {
         "drop" => {
             // (a ->)
-            if ctx.stack.len() < 1 {
-                eprintln!("Unsuficient data on stack for `drop`");
-                eprintln!("Type Stack: ");
-                eprintln!("\tActual: {:?}", ctx.stack.len());
-                eprintln!("\tExpected: {:?}", 1);
-                exit(-1);
+            if ctx.stack.is_empty() {
+                return Err("Unsuficient data on stack for `drop`".to_string());
             }
             let _ = ctx.stack.pop();
         }
         "dup" => {
             // (a -> a a)
-            if ctx.stack.len() < 1 {
-                eprintln!("Dup TODO");
-                exit(-1);
+            if ctx.stack.is_empty() {
+                return Err("Dup TODO".to_string());
             }
             let a = ctx.stack.pop().unwrap();
             ctx.stack.push(a);
@@ -331,8 +364,7 @@ fn check_intrinsic(s: &String, ctx: &mut TypeContext) {
         "swap" => {
             // (a b -> b a)
             if ctx.stack.len() < 2 {
-                eprintln!("Unsifsient data on stack for `swap`");
-                exit(-1);
+                return Err("Unsifsient data on stack for `swap`".to_string());
             }
             let b = ctx.stack.pop().unwrap();
             let a = ctx.stack.pop().unwrap();
@@ -342,9 +374,7 @@ fn check_intrinsic(s: &String, ctx: &mut TypeContext) {
         "over" => {
             // (a b -> a b a)
             if ctx.stack.len() < 2 {
-                dbg!(&ctx.stack);
-                eprintln!("Unsifsient data on stack for `over`");
-                exit(-1);
+                return Err("Unsifsient data on stack for `over`".to_string());
             }
             let b = ctx.stack.pop().unwrap();
             let a = ctx.stack.pop().unwrap();
@@ -355,8 +385,7 @@ fn check_intrinsic(s: &String, ctx: &mut TypeContext) {
         "rot" => {
             // (a b c -> b c a)
             if ctx.stack.len() < 3 {
-                eprintln!("Unsifsient data on stack for `rot`");
-                exit(-1);
+                return Err("Unsifsient data on stack for `rot`".to_string());
             }
             let c = ctx.stack.pop().unwrap();
             let b = ctx.stack.pop().unwrap();
@@ -368,144 +397,127 @@ fn check_intrinsic(s: &String, ctx: &mut TypeContext) {
         "offset" => {
             // (ptr int -> ptr)
             if ctx.stack.len() < 2 {
-                eprintln!("Args `offset` TODO");
-                exit(-1);
+                return Err("Args `offset` TODO".to_string());
             }
             if let Some(frame) = ctx.stack.pop() {
                 // b
                 if frame != DataType::Int {
-                    eprintln!("Typeof b `offset` Actual: {:?} TODO", frame);
-                    exit(-1);
+                    return Err(format!("Typeof b `offset` Actual: {:?} TODO", frame));
                 }
             }
             if let Some(frame) = ctx.stack.pop() {
                 // a
                 if frame != DataType::Ptr {
-                    eprintln!("Typeof a `offset` Actual: {:?} TODO", frame);
-                    exit(-1);
+                    return Err(format!("Typeof a `offset` Actual: {:?} TODO", frame));
                 }
             }
             ctx.stack.push(DataType::Ptr)
         }
-        "*" => {
-            // (int int -> int)
-            if ctx.stack.len() < 2 {
-                eprintln!("Args + TODO");
-                exit(-1);
-            }
-            if let Some(frame) = ctx.stack.pop() {
-                // b
-                if frame != DataType::Int {
-                    eprintln!("Typeof b * Actual: {:?} TODO", frame);
-                    exit(-1);
-                }
-            }
-            if let Some(frame) = ctx.stack.pop() {
-                // a
-                if frame != DataType::Int {
-                    eprintln!("Typeof a * TODO");
-                    exit(-1);
-                }
-            }
-            ctx.stack.push(DataType::Int)
+        "+" | "-" | "*" | "/" | "mod" | "&" | "|" | "^" => {
+            // (a a -> a) for the same integer type a
+            let typ = check_same_types(s, ctx)?;
+            if !typ.is_integer() {
+                return Err(format!("Expected integers for `{}`, found `{}`", s, typ));
+            }
+            ctx.stack.push(typ);
+            return Ok(Some(typ));
         }
-        "+" => {
-            // (int int -> int)
-            if ctx.stack.len() < 2 {
-                eprintln!("Args + TODO");
-                exit(-1);
-            }
-            if let Some(frame) = ctx.stack.pop() {
-                // b
-                if frame != DataType::Int {
-                    eprintln!("Typeof a `+` Actual: {:?} TODO", frame);
-                    exit(-1);
-                }
-            }
-            if let Some(frame) = ctx.stack.pop() {
-                // a
-                if frame != DataType::Int {
-                    eprintln!("Typeof a `+` Actual: {:?} TODO", frame);
-                    exit(-1);
-                }
-            }
-            ctx.stack.push(DataType::Int)
+        "<" | ">" | "<=" | ">=" => {
+            // (a a -> bool) for the same integer type a
+            let typ = check_same_types(s, ctx)?;
+            if !typ.is_integer() && typ != DataType::Char {
+                return Err(format!("Expected integers for `{}`, found `{}`", s, typ));
+            }
+            ctx.stack.push(DataType::Bool);
+            return Ok(Some(typ));
         }
-        "<" => {
-            // (int int -> bool)
-            if ctx.stack.len() < 2 {
-                eprintln!("Unsifsient data on stack for `<`");
-                exit(-1);
-            }
-            if let Some(frame) = ctx.stack.pop() {
-                // b
-                if frame != DataType::Int {
-                    eprintln!("Typeof b `<` Actual: {:?} TODO", frame);
-                    exit(-1);
-                }
-            }
-            if let Some(frame) = ctx.stack.pop() {
-                // a
-                if frame != DataType::Int {
-                    eprintln!("Typeof a `<` Actual: {:?} TODO", frame);
-                    exit(-1);
-                }
-            }
-            ctx.stack.push(DataType::Bool)
+        "==" | "!=" => {
+            // (a a -> bool)
+            let typ = check_same_types(s, ctx)?;
+            ctx.stack.push(DataType::Bool);
+            return Ok(Some(typ));
         }
-        "mod" => {
-            // (int int -> int)
+        "<<" | ">>" => {
+            // (a b -> a) for integers a and b
             if ctx.stack.len() < 2 {
-                eprintln!("Unsifsient data on stack for `mod`");
-                exit(-1);
+                return Err(format!("Unsifsient data on stack for `{}`", s));
             }
-            if let Some(frame) = ctx.stack.pop() {
-                // b
-                if frame != DataType::Int {
-                    eprintln!("Typeof b `mod` Actual: {:?} TODO", frame);
-                    exit(-1);
-                }
+            let b = ctx.stack.pop().unwrap();
+            let a = ctx.stack.pop().unwrap();
+            if !a.is_integer() || !b.is_integer() {
+                return Err(format!(
+                    "Expected integers for `{}`, found `{}` and `{}`",
+                    s, a, b
+                ));
             }
-            if let Some(frame) = ctx.stack.pop() {
-                // a
-                if frame != DataType::Int {
-                    eprintln!("Typeof a `mod` Actual: {:?} TODO", frame);
-                    exit(-1);
-                }
+            ctx.stack.push(a);
+            return Ok(Some(a));
+        }
+        "~" => {
+            // (a -> a) for an integer a
+            let Some(a) = ctx.stack.pop() else {
+                return Err("Unsifsient data on stack for `~`".to_string());
+            };
+            if !a.is_integer() {
+                return Err(format!("Expected an integer for `~`, found `{}`", a));
             }
-            ctx.stack.push(DataType::Int)
+            ctx.stack.push(a);
+            return Ok(Some(a));
         }
-        "!=" => {
-            // (int int -> bool)
-            if ctx.stack.len() < 2 {
-                eprintln!("!= TODO");
-                exit(-1);
+        "and" | "or" => {
+            // (bool bool -> bool)
+            let typ = check_same_types(s, ctx)?;
+            if typ != DataType::Bool {
+                return Err(format!("Expected `bool` for `{}`, found `{}`", s, typ));
             }
-            if let Some(frame) = ctx.stack.pop() {
-                // b
-                if frame != DataType::Int {
-                    eprintln!("!= TODO");
-                    exit(-1);
+            ctx.stack.push(DataType::Bool)
+        }
+        "not" => {
+            // (bool -> bool)
+            match ctx.stack.pop() {
+                Some(DataType::Bool) => ctx.stack.push(DataType::Bool),
+                Some(a) => {
+                    return Err(format!("Expected `bool` for `not`, found `{}`", a));
                 }
-            } else {
-                eprintln!("!= TODO");
-                exit(-1);
-            }
-            if let Some(frame) = ctx.stack.pop() {
-                // a
-                if frame != DataType::Int {
-                    eprintln!("!= TODO");
-                    exit(-1);
+                None => {
+                    return Err("Unsifsient data on stack for `not`".to_string());
                 }
-            } else {
-                eprintln!("!= TODO");
-                exit(-1);
             }
-            ctx.stack.push(DataType::Bool)
         }
         a => {
-            eprintln!("Unkwon instrinsic `{}` in type check", a);
-            exit(-1);
+            return Err(format!("Unkwon instrinsic `{}` in type check", a));
         }
     }
+    Ok(None)
+}
+
+/// Pop the two operands of a binary intrinsic, which must have the same type.
+/// Integers of different sizes or signedness are never mixed implicitly.
+fn check_same_types(s: &str, ctx: &mut TypeContext) -> CheckResult<DataType> {
+    if ctx.stack.len() < 2 {
+        return Err(format!("Unsifsient data on stack for `{}`", s));
+    }
+    let b = ctx.stack.pop().unwrap();
+    let a = ctx.stack.pop().unwrap();
+    if a != b {
+        return Err(format!(
+            "Cannot mix `{}` and `{}` in `{}`, cast one of them",
+            a, b, s
+        ));
+    }
+    Ok(a)
+}
+
+/// Integer types stored by `!N` and loaded by `@N`. `@N` gives the first.
+fn memory_types(bytes: usize) -> CheckResult<&'static [DataType]> {
+    match bytes {
+        8 => Ok(&[DataType::U8, DataType::I8]),
+        16 => Ok(&[DataType::U16, DataType::I16]),
+        32 => Ok(&[DataType::U32, DataType::I32, DataType::Char]),
+        64 => Ok(&[DataType::Int, DataType::U64]),
+        _ => Err(format!(
+            "Invalid size `{}`, expected 8, 16, 32 or 64",
+            bytes
+        )),
+    }
 }